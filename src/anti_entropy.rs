@@ -0,0 +1,349 @@
+// src/anti_entropy.rs
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::cache::Cache;
+use crate::hashing::ConsistentHashing;
+use crate::monitoring::Monitoring;
+use crate::proto::{KeyVersionsRequest, MerkleTreeRequest, TokenRange};
+use crate::replication::Replicator;
+
+/// Number of levels below the root; the tree has `2^MERKLE_DEPTH` leaves.
+pub const MERKLE_DEPTH: u32 = 10;
+
+/// Binary hash tree stored in heap order: index 0 is the root and the leaves
+/// occupy the last `2^depth` slots. Each leaf covers a slice of the token space.
+pub struct MerkleTree {
+    depth: u32,
+    hashes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Builds a tree from `(token, digest)` pairs. Leaf hashes are the wrapping
+    /// sum of their digests so that iteration order does not matter.
+    pub fn build(
+        hasher: &ConsistentHashing,
+        entries: impl Iterator<Item = (u64, u64)>,
+        depth: u32,
+    ) -> Self {
+        let leaves = 1usize << depth;
+        let mut hashes = vec![0u64; 2 * leaves - 1];
+        for (token, digest) in entries {
+            let index = leaves - 1 + Self::leaf_of(token, depth);
+            hashes[index] = hashes[index].wrapping_add(digest);
+        }
+        for index in (0..leaves - 1).rev() {
+            let mut children = [0u8; 16];
+            children[..8].copy_from_slice(&hashes[2 * index + 1].to_le_bytes());
+            children[8..].copy_from_slice(&hashes[2 * index + 2].to_le_bytes());
+            hashes[index] = hasher.hash(&children);
+        }
+        Self { depth, hashes }
+    }
+
+    pub fn from_hashes(depth: u32, hashes: Vec<u64>) -> Option<Self> {
+        if hashes.len() != (2usize << depth) - 1 {
+            return None;
+        }
+        Some(Self { depth, hashes })
+    }
+
+    pub fn into_hashes(self) -> Vec<u64> {
+        self.hashes
+    }
+
+    pub fn leaf_of(token: u64, depth: u32) -> usize {
+        if depth == 0 {
+            0
+        } else {
+            (token >> (64 - depth)) as usize
+        }
+    }
+
+    /// Returns the leaves whose hashes differ, descending only into subtrees
+    /// whose roots disagree.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        let leaves = 1usize << self.depth;
+        let mut differing = Vec::new();
+        let mut pending = vec![0usize];
+        while let Some(index) = pending.pop() {
+            if self.hashes[index] == other.hashes[index] {
+                continue;
+            }
+            if index >= leaves - 1 {
+                differing.push(index - (leaves - 1));
+            } else {
+                pending.push(2 * index + 1);
+                pending.push(2 * index + 2);
+            }
+        }
+        differing.sort_unstable();
+        differing
+    }
+}
+
+/// The keys two replicas are expected to hold in common: those in `ranges`
+/// whose owners include both `local` and `peer`.
+pub struct SyncScope<'a> {
+    pub ranges: &'a [(u64, u64)],
+    pub local: &'a str,
+    pub peer: &'a str,
+    pub replicas: usize,
+}
+
+/// Returns `(token, key, version)` for every live entry and tombstone in scope.
+pub fn collect_versions(
+    cache: &Cache,
+    hasher: &ConsistentHashing,
+    scope: &SyncScope,
+) -> Vec<(u64, String, u64)> {
+    let now = Instant::now();
+    let ranged = hasher.placement().ranged();
+    let in_ranges = |token: u64| {
+        scope
+            .ranges
+            .iter()
+            .any(|range| ConsistentHashing::in_range(token, *range))
+            && (ranged || hasher.shares(token, scope.local, scope.peer, scope.replicas))
+    };
+
+    let mut versions = Vec::new();
+    for entry in cache.data.iter() {
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        let token = hasher.key_token(entry.key());
+        if in_ranges(token) {
+            versions.push((token, entry.key().clone(), entry.version));
+        }
+    }
+    for tombstone in cache.tombstones.iter() {
+        let token = hasher.key_token(tombstone.key());
+        if in_ranges(token) {
+            versions.push((token, tombstone.key().clone(), tombstone.version));
+        }
+    }
+    versions
+}
+
+fn digest(hasher: &ConsistentHashing, key: &str, version: u64) -> u64 {
+    let mut bytes = Vec::with_capacity(key.len() + 8);
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend_from_slice(&version.to_le_bytes());
+    hasher.hash(&bytes)
+}
+
+pub fn build_tree(
+    cache: &Cache,
+    hasher: &ConsistentHashing,
+    scope: &SyncScope,
+    depth: u32,
+) -> MerkleTree {
+    let versions = collect_versions(cache, hasher, scope);
+    MerkleTree::build(
+        hasher,
+        versions
+            .iter()
+            .map(|(token, key, version)| (*token, digest(hasher, key, *version))),
+        depth,
+    )
+}
+
+pub fn to_token_ranges(ranges: &[(u64, u64)]) -> Vec<TokenRange> {
+    ranges
+        .iter()
+        .map(|(start, end)| TokenRange {
+            start: *start,
+            end: *end,
+        })
+        .collect()
+}
+
+pub fn from_token_ranges(ranges: &[TokenRange]) -> Vec<(u64, u64)> {
+    ranges
+        .iter()
+        .map(|range| (range.start, range.end))
+        .collect()
+}
+
+/// Periodically compares Merkle trees with every peer over the token ranges
+/// both nodes replicate, and exchanges only the keys that differ.
+pub struct AntiEntropy {
+    cache: Arc<Cache>,
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    monitoring: Arc<Monitoring>,
+    local_node_address: String,
+    interval: Duration,
+    rate: u64,
+}
+
+impl AntiEntropy {
+    pub fn new(
+        cache: Arc<Cache>,
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        monitoring: Arc<Monitoring>,
+        local_node_address: String,
+        config: &crate::config::Config,
+    ) -> Self {
+        Self {
+            cache,
+            hasher,
+            replicator,
+            monitoring,
+            local_node_address,
+            interval: Duration::from_secs(config.anti_entropy_interval),
+            rate: config.anti_entropy_rate.max(1),
+        }
+    }
+
+    pub async fn run(self) {
+        if self.interval.is_zero() {
+            info!("Anti-entropy is disabled");
+            return;
+        }
+        let mut ticker = interval(self.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for peer in self.hasher.get_all_nodes() {
+                if peer == self.local_node_address {
+                    continue;
+                }
+                self.sync_with(&peer).await;
+            }
+        }
+    }
+
+    async fn sync_with(&self, peer: &str) {
+        let replicas = self.replicator.replication_factor + 1;
+        let ranges = self
+            .hasher
+            .shared_ranges(&self.local_node_address, peer, replicas);
+        let scope = SyncScope {
+            ranges: &ranges,
+            local: &self.local_node_address,
+            peer,
+            replicas,
+        };
+        if ranges.is_empty() {
+            return;
+        }
+        let Some(mut client) = self.replicator.connect(peer).await else {
+            return;
+        };
+
+        let local_tree = build_tree(&self.cache, &self.hasher, &scope, MERKLE_DEPTH);
+        let request = MerkleTreeRequest {
+            ranges: to_token_ranges(&ranges),
+            depth: MERKLE_DEPTH,
+            node: self.local_node_address.clone(),
+        };
+        let remote_tree = match client
+            .get_merkle_tree(self.replicator.peer_request(request))
+            .await
+        {
+            Ok(response) => {
+                match MerkleTree::from_hashes(MERKLE_DEPTH, response.into_inner().hashes) {
+                    Some(tree) => tree,
+                    None => {
+                        warn!("Node {} returned a malformed Merkle tree", peer);
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Failed to fetch Merkle tree from node {}: {}", peer, e);
+                return;
+            }
+        };
+
+        let leaves = local_tree.diff(&remote_tree);
+        if leaves.is_empty() {
+            return;
+        }
+        info!(
+            "Anti-entropy with node {}: {} differing leaves",
+            peer,
+            leaves.len()
+        );
+
+        let request = KeyVersionsRequest {
+            ranges: to_token_ranges(&ranges),
+            depth: MERKLE_DEPTH,
+            leaves: leaves.iter().map(|leaf| *leaf as u32).collect(),
+            node: self.local_node_address.clone(),
+        };
+        let mut stream = match client
+            .get_key_versions(self.replicator.peer_request(request))
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                warn!("Failed to fetch key versions from node {}: {}", peer, e);
+                return;
+            }
+        };
+        let mut remote = HashMap::new();
+        loop {
+            match stream.message().await {
+                Ok(Some(key_version)) => {
+                    remote.insert(key_version.key, key_version.version);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Key version stream from node {} failed: {}", peer, e);
+                    return;
+                }
+            }
+        }
+
+        let mut local = HashMap::new();
+        for (token, key, version) in collect_versions(&self.cache, &self.hasher, &scope) {
+            if leaves
+                .binary_search(&MerkleTree::leaf_of(token, MERKLE_DEPTH))
+                .is_ok()
+            {
+                local.insert(key, version);
+            }
+        }
+
+        // Spread repairs out so that background sync does not compete with
+        // foreground traffic.
+        let mut limiter = interval(Duration::from_secs_f64(1.0 / self.rate as f64));
+        limiter.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        for (key, local_version) in &local {
+            let remote_version = remote.get(key).copied().unwrap_or(0);
+            if *local_version > remote_version {
+                if let Some(mutation) = self.cache.replica_state(key) {
+                    limiter.tick().await;
+                    self.replicator.send(peer, &mutation).await;
+                    self.record("push");
+                }
+            }
+        }
+        for (key, remote_version) in &remote {
+            if *remote_version > local.get(key).copied().unwrap_or(0) {
+                limiter.tick().await;
+                if let Some(Some(mutation)) = self.replicator.read_replica(peer, key).await {
+                    self.cache.apply_remote(mutation);
+                    self.record("pull");
+                }
+            }
+        }
+    }
+
+    fn record(&self, direction: &str) {
+        self.monitoring
+            .anti_entropy_repairs
+            .with_label_values(&[direction])
+            .inc();
+    }
+}
@@ -1,45 +1,160 @@
+use crate::counter::{CounterError, PnCounter};
 use crate::event_listener::{CacheEvent, EventType};
+use crate::replication::Mutation;
+use crate::structures::{Contents, Delta, SortedSet, Structure, StructureError};
+use crate::transaction_manager::{
+    IsolationLevel, Operation, Transaction, TransactionError, TransactionMode,
+};
 use bytes::Bytes;
 use crossbeam::channel::Sender;
 use dashmap::DashMap;
 use lz4::block::{compress, decompress};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Compresses a value into the representation stored in the cache and shipped
+/// to replicas, prefixed with its length, which `decode` needs.
+pub fn encode(value: &[u8]) -> Bytes {
+    Bytes::from(compress(value, None, true).unwrap())
+}
+
+/// Decompresses a stored value.
+pub fn decode(value: &[u8]) -> Option<Bytes> {
+    decompress(value, None).ok().map(Bytes::from)
+}
+
+/// What a compare-and-set expects to find.
+pub enum Expected {
+    /// The version of the current value; 0 for a key that does not exist.
+    Version(u64),
+    Value(Bytes),
+}
 
 pub struct CacheEntry {
     pub value: Bytes,
     pub expires_at: Option<Instant>,
     pub frequency: u64,
+    pub version: u64,
+    pub typed: Option<Typed>,
+}
+
+/// What an entry holds besides a plain value.
+#[derive(Clone)]
+pub enum Typed {
+    /// `value` holds the counter's current value as a decimal string.
+    Counter(PnCounter),
+    /// `value` is empty; the structure is kept uncompressed.
+    Structure(Structure),
+}
+
+impl CacheEntry {
+    pub fn counter(&self) -> Option<&PnCounter> {
+        match &self.typed {
+            Some(Typed::Counter(counter)) => Some(counter),
+            _ => None,
+        }
+    }
+
+    pub fn structure(&self) -> Option<&Structure> {
+        match &self.typed {
+            Some(Typed::Structure(structure)) => Some(structure),
+            _ => None,
+        }
+    }
+
+    /// Memory accounted to the entry.
+    pub fn size(&self) -> usize {
+        self.value.len()
+            + self.structure().map_or(0, Structure::size)
+            + self.counter().map_or(0, PnCounter::size)
+    }
 }
 
+/// Marker left behind by a delete so that older replicated writes for the
+/// same key are not resurrected.
+pub struct Tombstone {
+    pub version: u64,
+    pub expires_at: Instant,
+}
+
+const KEY_LOCK_STRIPES: usize = 64;
+
 pub struct Cache {
     max_memory: usize,
+    /// Entries read fewer times than this are on probation: they are evicted
+    /// before any other entry, oldest write first.
+    frequency_threshold: u64,
     pub current_memory: AtomicUsize,
     pub data: DashMap<String, CacheEntry>,
+    pub tombstones: DashMap<String, Tombstone>,
     pub default_ttl: Option<Duration>,
-    frequency_threshold: u64,
+    tombstone_ttl: Duration,
+    clock: AtomicU64,
     event_sender: Sender<CacheEvent>,
+    replication_sender: UnboundedSender<Mutation>,
+    /// Held for writing while a transaction commits and for reading by
+    /// `get_versioned`, so that readers never see part of a commit.
+    commit_lock: RwLock<()>,
+    transaction_mode: TransactionMode,
+    /// Keys written by prepared distributed transactions, and by which.
+    locks: Mutex<HashMap<String, String>>,
+    /// Replicated writes to locked keys, by the transaction holding the key.
+    /// They are applied on top of the transaction's writes once it resolves.
+    deferred: Mutex<HashMap<String, Vec<Mutation>>>,
+    /// This node's address, under which it counts its own counter updates.
+    node: String,
+    /// Serializes the read-modify-write of counter and structure updates, and
+    /// the merging of replicated counters.
+    update_lock: Mutex<()>,
+    /// Striped locks under which a write's version check and its store happen
+    /// together, so that an older replicated write cannot overwrite a newer one.
+    key_locks: Vec<Mutex<()>>,
     pub transaction_manager: Arc<crate::transaction_manager::TransactionManager>,
 }
 
 impl Cache {
-    pub fn new(config: crate::config::Config, event_sender: Sender<CacheEvent>) -> Self {
+    pub fn new(
+        config: crate::config::Config,
+        event_sender: Sender<CacheEvent>,
+        replication_sender: UnboundedSender<Mutation>,
+        monitoring: Arc<crate::monitoring::Monitoring>,
+        node: String,
+    ) -> Self {
         let transaction_manager = if config.enable_transactions {
             Arc::new(crate::transaction_manager::TransactionManager::new(
                 Duration::from_secs(config.transaction_timeout),
+                config.max_transactions_per_client,
+                monitoring,
             ))
         } else {
-            Arc::new(crate::transaction_manager::TransactionManager::disabled())
+            Arc::new(crate::transaction_manager::TransactionManager::disabled(
+                monitoring,
+            ))
         };
 
         Self {
             max_memory: config.max_memory,
+            frequency_threshold: config.frequency_threshold,
             current_memory: AtomicUsize::new(0),
             data: DashMap::new(),
+            tombstones: DashMap::new(),
             default_ttl: Some(Duration::from_secs(config.default_ttl)),
-            frequency_threshold: config.frequency_threshold,
+            tombstone_ttl: Duration::from_secs(config.tombstone_ttl),
+            clock: AtomicU64::new(0),
             event_sender,
+            replication_sender,
+            commit_lock: RwLock::new(()),
+            transaction_mode: config.transaction_mode,
+            locks: Mutex::new(HashMap::new()),
+            deferred: Mutex::new(HashMap::new()),
+            node,
+            update_lock: Mutex::new(()),
+            key_locks: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
             transaction_manager,
         }
     }
@@ -50,50 +165,431 @@ impl Cache {
             .load(std::sync::atomic::Ordering::SeqCst)
     }
 
-    /// Rollbacks the transaction with the given transaction ID.
-    pub fn rollback_transaction(&self, transaction_id: &str) {
-        if let Some(operations) = self.transaction_manager.rollback(transaction_id) {
-            for operation in operations {
-                match operation {
-                    crate::transaction_manager::Operation::Put { key, .. } => {
-                        // Remove the key if it was added during the transaction
-                        self.evict(&key);
-                    }
-                    crate::transaction_manager::Operation::Evict { key, value, ttl } => {
-                        // Restore the key if it was evicted during the transaction
-                        self.put(key, value, ttl);
-                    }
-                }
+    /// Rolls back the transaction with the given transaction ID and returns
+    /// how many of its writes were discarded. Buffered writes never reached
+    /// the cache; applied ones are undone from their before-images.
+    pub fn rollback_transaction(&self, transaction_id: &str) -> Result<usize, TransactionError> {
+        let transaction = self.transaction_manager.rollback(transaction_id)?;
+        let _guard = self.commit_lock.write().unwrap();
+        self.restore(&transaction);
+        Ok(transaction.operations.len())
+    }
+
+    /// Aborts a transaction on an administrator's request, undoing its applied
+    /// writes, and returns how many writes it had.
+    pub fn abort_transaction(&self, transaction_id: &str) -> Result<usize, TransactionError> {
+        let transaction = self.transaction_manager.abort(transaction_id)?;
+        let _guard = self.commit_lock.write().unwrap();
+        self.restore(&transaction);
+        Ok(transaction.operations.len())
+    }
+
+    /// Discards expired transactions, undoing their applied writes, and
+    /// returns how many there were.
+    pub fn cleanup_expired_transactions(&self) -> usize {
+        let expired = self.transaction_manager.cleanup_expired();
+        if expired.iter().any(|t| !t.before_images.is_empty()) {
+            let _guard = self.commit_lock.write().unwrap();
+            for transaction in &expired {
+                self.restore(transaction);
             }
-        } else {
-            tracing::warn!("Transaction ID {} not found for rollback.", transaction_id);
         }
+        expired.len()
     }
 
-    pub fn commit_transaction(&self, transaction_id: &str) {
-        if let Some(operations) = self.transaction_manager.commit(transaction_id) {
-            for operation in operations {
-                match operation {
-                    crate::transaction_manager::Operation::Put { key, value, ttl } => {
-                        self.put(key, value, ttl);
-                    }
-                    crate::transaction_manager::Operation::Evict { key, .. } => {
-                        self.evict(&key);
-                    }
-                    _ => {
-                        // Ignore other fields or log unexpected cases
-                        tracing::warn!("Unhandled transaction operation: {:?}", operation);
+    /// Begins a transaction for `client` whose snapshot starts at the current
+    /// version.
+    pub fn begin_transaction(
+        &self,
+        client: &str,
+        isolation: IsolationLevel,
+    ) -> Result<String, TransactionError> {
+        self.transaction_manager
+            .begin_transaction(client, isolation, self.next_version())
+    }
+
+    /// Reads `key` inside a transaction: its own writes and pinned reads
+    /// first, then the cache. Returns `None` on a local miss, which the caller
+    /// resolves like any other read and then passes to `record_read`, found
+    /// or not.
+    pub fn get_in_transaction(
+        &self,
+        transaction_id: &str,
+        key: &str,
+    ) -> Result<Option<Option<(Bytes, u64)>>, TransactionError> {
+        if let Some(known) = self.transaction_manager.read(transaction_id, key)? {
+            return Ok(Some(known));
+        }
+        let Some((value, version)) = self.get_versioned(key) else {
+            return Ok(None);
+        };
+        self.transaction_manager
+            .record_read(transaction_id, key, Some(value.clone()), version)?;
+        Ok(Some(Some((value, version))))
+    }
+
+    /// Records the result of a transaction's read that missed locally: a
+    /// value found elsewhere, or `None` if the key does not exist, which is
+    /// watched at the version of its tombstone, or 0.
+    pub fn record_read(
+        &self,
+        transaction_id: &str,
+        key: &str,
+        found: Option<(Bytes, u64)>,
+    ) -> Result<(), TransactionError> {
+        let (value, version) = match found {
+            Some((value, version)) => (Some(value), version),
+            None => (None, self.current_version(key)),
+        };
+        self.transaction_manager
+            .record_read(transaction_id, key, value, version)
+    }
+
+    /// Watches `keys` at their current versions, including keys that do not
+    /// exist yet.
+    pub fn watch(&self, transaction_id: &str, keys: &[String]) -> Result<(), TransactionError> {
+        for key in keys {
+            self.transaction_manager
+                .watch(transaction_id, key, self.current_version(key))?;
+        }
+        Ok(())
+    }
+
+    /// Makes a write in a transaction. In buffered mode it is only recorded;
+    /// in applied mode it reaches the cache at once, after the key's
+    /// before-image is saved.
+    pub fn write_in_transaction(
+        &self,
+        transaction_id: &str,
+        operation: Operation,
+    ) -> Result<(), TransactionError> {
+        if self.transaction_mode == TransactionMode::Buffered {
+            return self
+                .transaction_manager
+                .add_operation(transaction_id, operation);
+        }
+        let _guard = self.commit_lock.write().unwrap();
+        let key = operation.key();
+        if self
+            .locks
+            .lock()
+            .unwrap()
+            .get(key)
+            .is_some_and(|owner| owner != transaction_id)
+        {
+            return Err(TransactionError::Locked(key.to_string()));
+        }
+        let (previous, typed) = self
+            .data
+            .get(key)
+            .and_then(|entry| match entry.expires_at {
+                Some(expires_at) if expires_at <= Instant::now() => None,
+                expires_at => Some((Some((entry.value.clone(), expires_at)), entry.typed.clone())),
+            })
+            .unwrap_or_default();
+        let version = self.current_version(key);
+        let mutation = Self::mutation(&operation, self.next_version());
+        self.transaction_manager.add_applied(
+            transaction_id,
+            operation,
+            previous,
+            typed,
+            version,
+            mutation.version(),
+        )?;
+        self.apply(mutation);
+        Ok(())
+    }
+
+    /// Commits a transaction taken from the transaction manager on this node
+    /// alone. Buffered writes are applied as one unit: readers on this node
+    /// see either none or all of them, and memory-pressure eviction never
+    /// picks a key of the same commit. Replicas get the writes one by one.
+    /// The commit fails with `Conflict`, and nothing is applied (applied-mode
+    /// writes are undone), if a key the transaction read or watched has
+    /// changed since, if a key it writes is locked by a prepared distributed
+    /// transaction, or under snapshot isolation if another write to one of its
+    /// keys landed after the transaction began.
+    pub fn commit_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> Result<Vec<Operation>, TransactionError> {
+        let _guard = self.commit_lock.write().unwrap();
+
+        if let Some(key) = self.find_conflict(&transaction) {
+            self.restore(&transaction);
+            self.transaction_manager.record_outcome("aborted");
+            return Err(TransactionError::Conflict(key));
+        }
+
+        if transaction.before_images.is_empty() {
+            self.apply_batch(&transaction.operations);
+        }
+        self.transaction_manager.record_outcome("committed");
+        Ok(transaction.operations)
+    }
+
+    /// Validates the writes a distributed transaction has on this node and
+    /// locks their keys until `commit_prepared` or `unlock_keys`.
+    pub fn prepare(
+        &self,
+        transaction_id: &str,
+        operations: &[Operation],
+        watched: &HashMap<String, u64>,
+        snapshot_start: Option<u64>,
+    ) -> Result<(), TransactionError> {
+        let _guard = self.commit_lock.write().unwrap();
+        let written = operations.iter().map(|operation| operation.key());
+        if let Some(key) = self.version_conflict(transaction_id, watched, written, snapshot_start) {
+            return Err(TransactionError::Conflict(key));
+        }
+        self.lock_keys(transaction_id, operations);
+        Ok(())
+    }
+
+    /// Applies the writes of a prepared distributed transaction and unlocks
+    /// their keys.
+    pub fn commit_prepared(&self, transaction_id: &str, operations: &[Operation]) {
+        let _guard = self.commit_lock.write().unwrap();
+        self.apply_batch(operations);
+        let written = operations.iter().map(Operation::key).collect();
+        self.release(transaction_id, &written);
+    }
+
+    pub fn lock_keys(&self, transaction_id: &str, operations: &[Operation]) {
+        let mut locks = self.locks.lock().unwrap();
+        for operation in operations {
+            locks.insert(operation.key().to_string(), transaction_id.to_string());
+        }
+    }
+
+    /// Releases the keys of a transaction that wrote nothing here, and applies
+    /// the replicated writes that arrived for them meanwhile.
+    pub fn unlock_keys(&self, transaction_id: &str) {
+        let _commit = self.commit_lock.read().unwrap();
+        self.release(transaction_id, &HashSet::new());
+    }
+
+    /// Releases the keys of a transaction and applies the replicated writes
+    /// that arrived for them meanwhile as `apply_remote` does, under their own
+    /// versions. Those were made concurrently with the transaction but reached
+    /// this node after it locked the keys, so one that would lose to the
+    /// transaction's write of a key in `written` is ordered after it instead:
+    /// it gets a new version and is replicated again. Counters merging into a
+    /// counter and structure deltas are never re-versioned. The caller holds
+    /// the commit lock.
+    fn release(&self, transaction_id: &str, written: &HashSet<&str>) {
+        self.locks
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != transaction_id);
+        let deferred = self.deferred.lock().unwrap().remove(transaction_id);
+        for mutation in deferred.into_iter().flatten() {
+            let merges = match &mutation {
+                Mutation::Counter { key, .. } => self
+                    .data
+                    .get(key)
+                    .is_some_and(|entry| entry.counter().is_some()),
+                Mutation::StructureDelta { .. } => true,
+                _ => false,
+            };
+            if written.contains(mutation.key()) && !merges {
+                self.apply(mutation.with_version(self.next_version()));
+            } else {
+                self.accept(mutation);
+            }
+        }
+    }
+
+    /// Holds off commits and prepares while a local write to `key` is made.
+    /// Returns `None` instead if a prepared distributed transaction holds the
+    /// key, as its commit would overwrite the write.
+    fn write_guard(&self, key: &str) -> Option<RwLockReadGuard<'_, ()>> {
+        let guard = self.commit_lock.read().unwrap();
+        (!self.locks.lock().unwrap().contains_key(key)).then_some(guard)
+    }
+
+    /// Returns a key whose changes since the transaction read, watched or
+    /// wrote it make the transaction conflict.
+    fn find_conflict(&self, transaction: &Transaction) -> Option<String> {
+        // Applied writes: nobody wrote the key between the transaction's read
+        // or watch and its write, nor after its write
+        let snapshot_start = (transaction.isolation == IsolationLevel::Snapshot)
+            .then_some(transaction.start_version);
+        if let Some((key, _)) = transaction.before_images.iter().find(|(key, image)| {
+            image.version != image.expected
+                || self.current_version(key) != image.written
+                || snapshot_start.is_some_and(|start| image.version > start)
+        }) {
+            return Some(key.clone());
+        }
+        let buffered = transaction
+            .operations
+            .iter()
+            .map(|operation| operation.key())
+            .filter(|key| !transaction.before_images.contains_key(*key));
+        self.version_conflict(
+            &transaction.id,
+            &transaction.watched,
+            buffered,
+            snapshot_start,
+        )
+    }
+
+    /// Returns a watched key that changed, or a written key that is locked by
+    /// another transaction or, given a snapshot start, was written after it.
+    fn version_conflict<'a>(
+        &self,
+        transaction_id: &str,
+        watched: &HashMap<String, u64>,
+        written: impl IntoIterator<Item = &'a str>,
+        snapshot_start: Option<u64>,
+    ) -> Option<String> {
+        if let Some((key, _)) = watched
+            .iter()
+            .find(|(key, version)| self.current_version(key) != **version)
+        {
+            return Some(key.clone());
+        }
+        let locks = self.locks.lock().unwrap();
+        written
+            .into_iter()
+            .find(|key| {
+                locks.get(*key).is_some_and(|owner| owner != transaction_id)
+                    || snapshot_start.is_some_and(|start| self.current_version(key) > start)
+            })
+            .map(str::to_string)
+    }
+
+    /// Applies writes as one batch; the caller holds the commit lock.
+    fn apply_batch(&self, operations: &[Operation]) {
+        let keys: HashSet<String> = operations
+            .iter()
+            .map(|operation| operation.key().to_string())
+            .collect();
+        for operation in operations {
+            let mutation = Self::mutation(operation, self.next_version());
+            let guard = self.key_lock(mutation.key());
+            self.store(&mutation, &keys);
+            drop(guard);
+            let _ = self.replication_sender.send(mutation);
+        }
+    }
+
+    /// Puts the keys an applied-mode transaction wrote back the way they were,
+    /// with their original expiry, counters and structures included. The
+    /// restored state gets a new version so that replicas take it. Keys written
+    /// by someone else since are left alone.
+    fn restore(&self, transaction: &Transaction) {
+        for (key, image) in &transaction.before_images {
+            if self.current_version(key) != image.written {
+                continue;
+            }
+            let now = Instant::now();
+            let version = self.next_version();
+            let mutation = match &image.value {
+                Some((value, expires_at)) if expires_at.is_none_or(|at| at > now) => {
+                    let ttl = expires_at.map(|at| at - now);
+                    match &image.typed {
+                        Some(Typed::Counter(counter)) => Mutation::Counter {
+                            key: key.clone(),
+                            counter: counter.clone(),
+                            ttl,
+                            version,
+                        },
+                        Some(Typed::Structure(structure)) => Mutation::Structure {
+                            key: key.clone(),
+                            structure: structure.clone(),
+                            ttl,
+                            version,
+                        },
+                        None => Mutation::Put {
+                            key: key.clone(),
+                            value: value.clone(),
+                            ttl,
+                            version,
+                        },
                     }
                 }
-            }
+                _ => Mutation::Delete {
+                    key: key.clone(),
+                    version,
+                },
+            };
+            self.apply(mutation);
+        }
+    }
+
+    fn mutation(operation: &Operation, version: u64) -> Mutation {
+        match operation {
+            Operation::Put { key, value, ttl } => Mutation::Put {
+                key: key.clone(),
+                value: encode(value),
+                ttl: *ttl,
+                version,
+            },
+            Operation::Evict { key } => Mutation::Delete {
+                key: key.clone(),
+                version,
+            },
+        }
+    }
+
+    /// Writes `value` only if the key matches `expected`, atomically with
+    /// respect to commits and other compare-and-sets on this node, which must
+    /// be the key's owner for the check to hold cluster-wide. Returns the new
+    /// version, or the current version (0 if the key does not exist) on a
+    /// mismatch, and fails with `Locked` while a prepared distributed
+    /// transaction holds the key.
+    pub fn compare_and_set(
+        &self,
+        key: String,
+        expected: Expected,
+        value: Bytes,
+        ttl: Option<Duration>,
+    ) -> Result<Result<u64, u64>, TransactionError> {
+        let _guard = self.commit_lock.write().unwrap();
+        if self.locks.lock().unwrap().contains_key(&key) {
+            return Err(TransactionError::Locked(key));
+        }
+        let current = self
+            .data
+            .get(&key)
+            .and_then(|entry| match entry.expires_at {
+                Some(expires_at) if expires_at <= Instant::now() => None,
+                _ => Some((entry.version, entry.value.clone())),
+            });
+        let matches = match (&expected, &current) {
+            (Expected::Version(version), Some((current, _))) => version == current,
+            (Expected::Version(version), None) => *version == 0,
+            (Expected::Value(value), Some((_, current))) => decode(current).as_ref() == Some(value),
+            (Expected::Value(_), None) => false,
+        };
+        if !matches {
+            return Ok(Err(current.map_or(0, |(version, _)| version)));
         }
+        let version = self.next_version();
+        self.apply(Mutation::Put {
+            key,
+            value: encode(&value),
+            ttl,
+            version,
+        });
+        Ok(Ok(version))
     }
 
     pub fn get(&self, key: &str) -> Option<Bytes> {
+        self.get_versioned(key).map(|(value, _)| value)
+    }
+
+    /// Returns the value together with the version of the write that produced it.
+    pub fn get_versioned(&self, key: &str) -> Option<(Bytes, u64)> {
+        let _commit = self.commit_lock.read().unwrap();
         if let Some(mut entry) = self.data.get_mut(key) {
             if let Some(expires_at) = entry.expires_at {
                 if Instant::now() > expires_at {
-                    let size = entry.value.len();
+                    let size = entry.size();
+                    drop(entry);
                     self.data.remove(key);
                     self.current_memory.fetch_sub(size, Ordering::SeqCst);
                     let _ = self.event_sender.send(CacheEvent {
@@ -104,23 +600,292 @@ impl Cache {
                 }
             }
             entry.frequency += 1;
-            decompress(&entry.value, None).ok().map(Bytes::from)
+            let version = entry.version;
+            if let Some(structure) = entry.structure() {
+                return Some((structure.render(), version));
+            }
+            decode(&entry.value).map(|value| (value, version))
         } else {
             None
         }
     }
 
-    pub fn put(&self, key: String, value: Bytes, ttl: Option<Duration>) {
-        let expires_at = ttl.map(|t| Instant::now() + t);
-        let compressed_value = compress(&value, None, false).unwrap();
-        let size = compressed_value.len();
+    /// How long the value at `key` has left to live, if it expires.
+    pub fn remaining_ttl(&self, key: &str) -> Option<Duration> {
+        let expires_at = self.data.get(key)?.expires_at?;
+        Some(expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the local state of a key as a mutation, including tombstones,
+    /// so that replicas can be compared by version.
+    pub fn replica_state(&self, key: &str) -> Option<Mutation> {
+        if let Some(entry) = self.data.get(key) {
+            let now = Instant::now();
+            let ttl = match entry.expires_at {
+                Some(expires_at) if expires_at <= now => return None,
+                Some(expires_at) => Some(expires_at - now),
+                None => None,
+            };
+            match &entry.typed {
+                Some(Typed::Counter(counter)) => {
+                    return Some(Mutation::Counter {
+                        key: key.to_string(),
+                        counter: counter.clone(),
+                        ttl,
+                        version: entry.version,
+                    })
+                }
+                Some(Typed::Structure(structure)) => {
+                    return Some(Mutation::Structure {
+                        key: key.to_string(),
+                        structure: structure.clone(),
+                        ttl,
+                        version: entry.version,
+                    })
+                }
+                None => {}
+            }
+            return Some(Mutation::Put {
+                key: key.to_string(),
+                value: entry.value.clone(),
+                ttl,
+                version: entry.version,
+            });
+        }
+        self.tombstones.get(key).map(|tombstone| Mutation::Delete {
+            key: key.to_string(),
+            version: tombstone.version,
+        })
+    }
+
+    /// Stores a value written on this node and replicates it to the key's replicas.
+    /// Returns the version assigned to the write.
+    pub fn put(
+        &self,
+        key: String,
+        value: Bytes,
+        ttl: Option<Duration>,
+    ) -> Result<u64, TransactionError> {
+        let Some(_commit) = self.write_guard(&key) else {
+            return Err(TransactionError::Locked(key));
+        };
+        let version = self.next_version();
+        self.apply(Mutation::Put {
+            key,
+            value: encode(&value),
+            ttl,
+            version,
+        });
+        Ok(version)
+    }
+
+    /// Deletes a key on this node and replicates the delete as a tombstone.
+    pub fn evict(&self, key: &str) -> Result<(), TransactionError> {
+        let Some(_commit) = self.write_guard(key) else {
+            return Err(TransactionError::Locked(key.to_string()));
+        };
+        let version = self.next_version();
+        self.apply(Mutation::Delete {
+            key: key.to_string(),
+            version,
+        });
+        Ok(())
+    }
+
+    /// Removes a key this node no longer owns, without leaving a tombstone or
+    /// replicating the removal.
+    pub fn discard(&self, key: &str) {
+        self.tombstones.remove(key);
+        if let Some((_, entry)) = self.data.remove(key) {
+            self.current_memory
+                .fetch_sub(entry.size(), Ordering::SeqCst);
+            let _ = self.event_sender.send(CacheEvent {
+                event_type: EventType::Evict,
+                key: key.to_string(),
+            });
+        }
+    }
+
+    /// Applies a mutation received from a peer. Returns `false` when the local
+    /// copy is already at the same or a newer version. Counters are merged
+    /// into the local state instead. A mutation of a key locked by a prepared
+    /// distributed transaction waits until the transaction resolves.
+    pub fn apply_remote(&self, mutation: Mutation) -> bool {
+        self.observe_version(mutation.version());
+        let _commit = self.commit_lock.read().unwrap();
+        if let Some(owner) = self.locks.lock().unwrap().get(mutation.key()) {
+            self.deferred
+                .lock()
+                .unwrap()
+                .entry(owner.clone())
+                .or_default()
+                .push(mutation);
+            return true;
+        }
+        self.accept(mutation)
+    }
+
+    /// Applies a replicated mutation, merging counters and keeping whatever
+    /// supersedes it. The caller holds the commit lock.
+    fn accept(&self, mutation: Mutation) -> bool {
+        if let Mutation::Counter { .. } = mutation {
+            let _guard = self.update_lock.lock().unwrap();
+            let _key = self.key_lock(mutation.key());
+            return self.merge_counter(&mutation);
+        }
+        let _key = self.key_lock(mutation.key());
+        if !self.supersedes(&mutation) {
+            return false;
+        }
+        self.store(&mutation, &HashSet::new());
+        true
+    }
+
+    /// Single choke point for every local mutation: applies it and hands it to
+    /// the replicator.
+    fn apply(&self, mutation: Mutation) {
+        let guard = self.key_lock(mutation.key());
+        self.store(&mutation, &HashSet::new());
+        drop(guard);
+        let _ = self.replication_sender.send(mutation);
+    }
+
+    /// Like `apply`, but replicates `replicated`, which has the same effect
+    /// on a replica that is up to date, instead of `mutation`.
+    fn apply_as(&self, mutation: Mutation, replicated: Mutation) {
+        let guard = self.key_lock(mutation.key());
+        self.store(&mutation, &HashSet::new());
+        drop(guard);
+        let _ = self.replication_sender.send(replicated);
+    }
+
+    /// Stores a mutation locally. Keys in `protected` are never evicted to make
+    /// room for it.
+    fn store(&self, mutation: &Mutation, protected: &HashSet<String>) {
+        match mutation {
+            Mutation::Put {
+                key,
+                value,
+                ttl,
+                version,
+            } => {
+                self.tombstones.remove(key);
+                self.insert(key.clone(), value, *ttl, *version, None, protected);
+            }
+            Mutation::Counter {
+                key,
+                counter,
+                ttl,
+                version,
+            } => {
+                self.tombstones.remove(key);
+                let value = encode(counter.value().to_string().as_bytes());
+                self.insert(
+                    key.clone(),
+                    &value,
+                    *ttl,
+                    *version,
+                    Some(Typed::Counter(counter.clone())),
+                    protected,
+                );
+            }
+            Mutation::Structure {
+                key,
+                structure,
+                ttl,
+                version,
+            } => {
+                self.tombstones.remove(key);
+                self.insert(
+                    key.clone(),
+                    &Bytes::new(),
+                    *ttl,
+                    *version,
+                    Some(Typed::Structure(structure.clone())),
+                    protected,
+                );
+            }
+            Mutation::StructureDelta {
+                key,
+                delta,
+                version,
+                ..
+            } => {
+                let Some((mut structure, expires_at)) = self
+                    .data
+                    .get(key)
+                    .and_then(|entry| Some((entry.structure()?.clone(), entry.expires_at)))
+                else {
+                    return;
+                };
+                delta.apply(&mut structure);
+                let ttl = expires_at.map(|at| at.saturating_duration_since(Instant::now()));
+                self.insert(
+                    key.clone(),
+                    &Bytes::new(),
+                    ttl,
+                    *version,
+                    Some(Typed::Structure(structure)),
+                    protected,
+                );
+            }
+            Mutation::Delete { key, version } => {
+                self.tombstones.insert(
+                    key.clone(),
+                    Tombstone {
+                        version: *version,
+                        expires_at: Instant::now() + self.tombstone_ttl,
+                    },
+                );
+                if let Some((_, entry)) = self.data.remove(key) {
+                    self.current_memory
+                        .fetch_sub(entry.size(), Ordering::SeqCst);
+                    let _ = self.event_sender.send(CacheEvent {
+                        event_type: EventType::Evict,
+                        key: key.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        value: &Bytes,
+        ttl: Option<Duration>,
+        version: u64,
+        typed: Option<Typed>,
+        protected: &HashSet<String>,
+    ) {
+        let entry = CacheEntry {
+            value: value.clone(),
+            expires_at: ttl.map(|t| Instant::now() + t),
+            frequency: 1,
+            version,
+            typed,
+        };
+        let size = entry.size();
 
         while self.current_memory.load(Ordering::SeqCst) + size > self.max_memory {
-            if let Some(item) = self.data.iter().min_by_key(|entry| entry.value().frequency) {
+            if let Some(item) = self
+                .data
+                .iter()
+                .filter(|entry| !protected.contains(entry.key()))
+                .min_by_key(|entry| {
+                    let entry = entry.value();
+                    if entry.frequency < self.frequency_threshold {
+                        (false, entry.version)
+                    } else {
+                        (true, entry.frequency)
+                    }
+                })
+            {
                 let key = item.key().clone();
+                drop(item);
                 let entry = self.data.remove(&key).unwrap().1;
                 self.current_memory
-                    .fetch_sub(entry.value.len(), Ordering::SeqCst);
+                    .fetch_sub(entry.size(), Ordering::SeqCst);
                 let _ = self.event_sender.send(CacheEvent {
                     event_type: EventType::Evict,
                     key,
@@ -130,29 +895,580 @@ impl Cache {
             }
         }
 
-        self.data.insert(
-            key.clone(),
-            CacheEntry {
-                value: Bytes::from(compressed_value),
-                expires_at,
-                frequency: 1,
-            },
-        );
+        let previous = self.data.insert(key.clone(), entry);
         self.current_memory.fetch_add(size, Ordering::SeqCst);
+        if let Some(previous) = previous {
+            self.current_memory
+                .fetch_sub(previous.size(), Ordering::SeqCst);
+        }
         let _ = self.event_sender.send(CacheEvent {
             event_type: EventType::Put,
             key,
         });
     }
 
-    pub fn evict(&self, key: &str) {
-        if let Some(entry) = self.data.remove(key) {
-            self.current_memory
-                .fetch_sub(entry.1.value.len(), Ordering::SeqCst);
-            let _ = self.event_sender.send(CacheEvent {
-                event_type: EventType::Evict,
+    /// Adds `delta` to the counter at `key` and returns its new value and
+    /// version. A missing counter is created from `initial` with `ttl`; an
+    /// existing one keeps its expiry. A plain value that parses as an integer
+    /// becomes a counter starting from it. A new counter's lifetime is the
+    /// version of what it replaces, so that replicas creating it at the same
+    /// time merge their updates.
+    pub fn increment(
+        &self,
+        key: &str,
+        delta: i64,
+        initial: i64,
+        ttl: Option<Duration>,
+    ) -> Result<(i64, u64), CounterError> {
+        let Some(_commit) = self.write_guard(key) else {
+            return Err(CounterError::Locked(key.to_string()));
+        };
+        let _guard = self.update_lock.lock().unwrap();
+        let now = Instant::now();
+        let existing = self.data.get(key).and_then(|entry| match entry.expires_at {
+            Some(expires_at) if expires_at <= now => None,
+            expires_at => Some((
+                entry.counter().cloned(),
+                entry.value.clone(),
+                expires_at.map(|at| at - now),
+            )),
+        });
+        let version = self.next_version();
+        let (mut counter, ttl) = match existing {
+            Some((Some(counter), _, remaining)) => (counter, remaining),
+            Some((None, value, remaining)) => {
+                let initial = decode(&value)
+                    .and_then(|value| std::str::from_utf8(&value).ok()?.trim().parse().ok())
+                    .ok_or_else(|| CounterError::NotACounter(key.to_string()))?;
+                (
+                    PnCounter::new(self.current_version(key), initial),
+                    remaining,
+                )
+            }
+            None => (PnCounter::new(self.current_version(key), initial), ttl),
+        };
+        let value = counter.add(&self.node, delta)?;
+        self.apply(Mutation::Counter {
+            key: key.to_string(),
+            counter,
+            ttl,
+            version,
+        });
+        Ok((value, version))
+    }
+
+    /// Merges a replicated counter into the local state; the caller holds the
+    /// key's lock. Counters of the same lifetime merge and a later lifetime
+    /// replaces an earlier one. A delete after the counter's lifetime began
+    /// wins over it; a plain value wins only over a counter last updated
+    /// before it. A merge that yields more than the replicated state gets a
+    /// new version and is replicated back, since the two sides may have held
+    /// their states under the same version, which anti-entropy and read
+    /// repair could not tell apart. Returns whether anything changed.
+    fn merge_counter(&self, mutation: &Mutation) -> bool {
+        let Mutation::Counter {
+            key,
+            counter,
+            ttl,
+            version,
+        } = mutation
+        else {
+            return false;
+        };
+        if self
+            .tombstones
+            .get(key)
+            .is_some_and(|tombstone| tombstone.version > counter.created)
+        {
+            return false;
+        }
+        let local = self
+            .data
+            .get(key)
+            .map(|entry| (entry.counter().cloned(), entry.version));
+        let (merged, version, new) = match local {
+            Some((Some(local), local_version)) if local.created == counter.created => {
+                let mut merged = local.clone();
+                merged.merge(counter);
+                if merged == local && local_version >= *version {
+                    return false;
+                }
+                if merged != *counter {
+                    (merged, self.next_version(), true)
+                } else {
+                    (merged, local_version.max(*version), false)
+                }
+            }
+            Some((Some(local), _)) if local.created > counter.created => return false,
+            Some((None, local_version)) if local_version > *version => return false,
+            _ => (counter.clone(), *version, false),
+        };
+        let merged = Mutation::Counter {
+            key: key.clone(),
+            counter: merged,
+            ttl: *ttl,
+            version,
+        };
+        self.store(&merged, &HashSet::new());
+        if new {
+            let _ = self.replication_sender.send(merged);
+        }
+        true
+    }
+
+    /// Sets fields of the hash at `key` and returns how many were new.
+    pub fn hash_set(
+        &self,
+        key: &str,
+        fields: Vec<(String, Bytes)>,
+        ttl: Option<Duration>,
+    ) -> Result<(usize, u64), StructureError> {
+        self.update_structure(key, ttl, |hash: &mut BTreeMap<String, Bytes>| {
+            let mut added = 0;
+            let mut changed = Vec::new();
+            for (field, value) in fields {
+                match hash.insert(field.clone(), value.clone()) {
+                    Some(previous) if previous == value => continue,
+                    Some(_) => {}
+                    None => added += 1,
+                }
+                changed.push((field, value));
+            }
+            let delta = (!changed.is_empty()).then_some(Delta::HashSet { fields: changed });
+            (added, delta)
+        })
+    }
+
+    /// Returns the requested fields of the hash at `key` that exist, or all of
+    /// them if `fields` is empty.
+    pub fn hash_get(
+        &self,
+        key: &str,
+        fields: &[String],
+    ) -> Result<Vec<(String, Bytes)>, StructureError> {
+        self.read_structure(key, |hash: &BTreeMap<String, Bytes>| {
+            if fields.is_empty() {
+                return hash
+                    .iter()
+                    .map(|(field, value)| (field.clone(), value.clone()))
+                    .collect();
+            }
+            fields
+                .iter()
+                .filter_map(|field| Some((field.clone(), hash.get(field)?.clone())))
+                .collect()
+        })
+    }
+
+    /// Removes fields from the hash at `key` and returns how many existed.
+    pub fn hash_delete(
+        &self,
+        key: &str,
+        fields: &[String],
+    ) -> Result<(usize, u64), StructureError> {
+        self.update_structure(key, None, |hash: &mut BTreeMap<String, Bytes>| {
+            let removed: Vec<String> = fields
+                .iter()
+                .filter(|field| hash.remove(*field).is_some())
+                .cloned()
+                .collect();
+            let delta = (!removed.is_empty()).then_some(Delta::HashDelete {
+                fields: removed.clone(),
+            });
+            (removed.len(), delta)
+        })
+    }
+
+    /// Pushes values onto the front or back of the list at `key`, in order,
+    /// and returns its new length.
+    pub fn list_push(
+        &self,
+        key: &str,
+        values: Vec<Bytes>,
+        front: bool,
+        ttl: Option<Duration>,
+    ) -> Result<(usize, u64), StructureError> {
+        self.update_structure(key, ttl, |list: &mut VecDeque<Bytes>| {
+            let delta = (!values.is_empty()).then(|| Delta::ListPush {
+                values: values.clone(),
+                front,
+            });
+            for value in values {
+                if front {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            (list.len(), delta)
+        })
+    }
+
+    /// Removes up to `count` values from the front or back of the list at
+    /// `key` and returns them in the order they were removed.
+    pub fn list_pop(
+        &self,
+        key: &str,
+        count: usize,
+        front: bool,
+    ) -> Result<(Vec<Bytes>, u64), StructureError> {
+        self.update_structure(key, None, |list: &mut VecDeque<Bytes>| {
+            let count = count.min(list.len());
+            let popped: Vec<Bytes> = if front {
+                list.drain(..count).collect()
+            } else {
+                list.drain(list.len() - count..).rev().collect()
+            };
+            let delta = (count > 0).then_some(Delta::ListPop { count, front });
+            (popped, delta)
+        })
+    }
+
+    /// Returns the values of the list at `key` from `start` to `stop`
+    /// inclusive, where negative positions count from the end.
+    pub fn list_range(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Bytes>, StructureError> {
+        self.read_structure(key, |list: &VecDeque<Bytes>| {
+            let len = list.len() as i64;
+            let position = |index: i64| if index < 0 { len + index } else { index };
+            let start = position(start).max(0);
+            let stop = position(stop).min(len - 1);
+            if start > stop {
+                return Vec::new();
+            }
+            list.range(start as usize..=stop as usize)
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Adds members to the set at `key` and returns how many were new.
+    pub fn set_add(
+        &self,
+        key: &str,
+        members: Vec<Bytes>,
+        ttl: Option<Duration>,
+    ) -> Result<(usize, u64), StructureError> {
+        self.update_structure(key, ttl, |set: &mut BTreeSet<Bytes>| {
+            let added: Vec<Bytes> = members
+                .into_iter()
+                .filter(|member| set.insert(member.clone()))
+                .collect();
+            let delta = (!added.is_empty()).then(|| Delta::SetAdd {
+                members: added.clone(),
+            });
+            (added.len(), delta)
+        })
+    }
+
+    /// Removes members from the set at `key` and returns how many existed.
+    pub fn set_remove(&self, key: &str, members: &[Bytes]) -> Result<(usize, u64), StructureError> {
+        self.update_structure(key, None, |set: &mut BTreeSet<Bytes>| {
+            let removed: Vec<Bytes> = members
+                .iter()
+                .filter(|member| set.remove(*member))
+                .cloned()
+                .collect();
+            let delta = (!removed.is_empty()).then(|| Delta::SetRemove {
+                members: removed.clone(),
+            });
+            (removed.len(), delta)
+        })
+    }
+
+    pub fn set_members(&self, key: &str) -> Result<Vec<Bytes>, StructureError> {
+        self.read_structure(key, |set: &BTreeSet<Bytes>| set.iter().cloned().collect())
+    }
+
+    /// Adds members to the sorted set at `key`, or updates their scores, and
+    /// returns how many were new.
+    pub fn sorted_set_add(
+        &self,
+        key: &str,
+        members: Vec<(Bytes, f64)>,
+        ttl: Option<Duration>,
+    ) -> Result<(usize, u64), StructureError> {
+        if members.iter().any(|(_, score)| score.is_nan()) {
+            return Err(StructureError::InvalidScore);
+        }
+        self.update_structure(key, ttl, |sorted: &mut SortedSet| {
+            let mut added = 0;
+            let mut changed = Vec::new();
+            for (member, score) in members {
+                if sorted.score(&member) == Some(score) {
+                    continue;
+                }
+                if sorted.insert(member.clone(), score) {
+                    added += 1;
+                }
+                changed.push((member, score));
+            }
+            let delta = (!changed.is_empty()).then_some(Delta::SortedSetAdd { members: changed });
+            (added, delta)
+        })
+    }
+
+    /// Returns up to `limit` members of the sorted set at `key` with a score
+    /// in `min..=max`, lowest score first.
+    pub fn sorted_set_range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        limit: usize,
+    ) -> Result<Vec<(Bytes, f64)>, StructureError> {
+        self.read_structure(key, |sorted: &SortedSet| {
+            sorted.range_by_score(min, max, limit)
+        })
+    }
+
+    /// Returns the zero-based rank and the score of a member of the sorted set
+    /// at `key`.
+    pub fn sorted_set_rank(
+        &self,
+        key: &str,
+        member: &[u8],
+    ) -> Result<Option<(usize, f64)>, StructureError> {
+        self.read_structure(key, |sorted: &SortedSet| sorted.rank(member))
+    }
+
+    /// Reads the structure at `key`, which must be of the type `read` takes.
+    /// A missing key reads as empty.
+    fn read_structure<C: Contents, T>(
+        &self,
+        key: &str,
+        read: impl FnOnce(&C) -> T,
+    ) -> Result<T, StructureError> {
+        let _commit = self.commit_lock.read().unwrap();
+        match self.data.get_mut(key) {
+            Some(mut entry) if entry.expires_at.is_none_or(|at| at > Instant::now()) => {
+                entry.frequency += 1;
+                let contents = entry
+                    .structure()
+                    .and_then(C::peek)
+                    .ok_or_else(|| StructureError::WrongType(key.to_string()))?;
+                Ok(read(contents))
+            }
+            _ => Ok(read(&C::default())),
+        }
+    }
+
+    /// Updates the structure at `key`, which must be of the type `update`
+    /// takes. A missing structure is created with `ttl` and replicated whole;
+    /// an existing one keeps its expiry and replicates only the delta, and one
+    /// left empty is deleted. `update` returns its result and the delta, if it
+    /// changed anything. Returns the result and the version of the structure.
+    fn update_structure<C: Contents, T>(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        update: impl FnOnce(&mut C) -> (T, Option<Delta>),
+    ) -> Result<(T, u64), StructureError> {
+        let Some(_commit) = self.write_guard(key) else {
+            return Err(StructureError::Locked(key.to_string()));
+        };
+        let _guard = self.update_lock.lock().unwrap();
+        let now = Instant::now();
+        let existing = self.data.get(key).and_then(|entry| match entry.expires_at {
+            Some(expires_at) if expires_at <= now => None,
+            expires_at => Some((
+                entry.structure().cloned(),
+                entry.version,
+                expires_at.map(|at| at - now),
+            )),
+        });
+        let (mut contents, ttl, current, created) = match existing {
+            Some((structure, version, remaining)) => (
+                structure
+                    .and_then(C::unwrap)
+                    .ok_or_else(|| StructureError::WrongType(key.to_string()))?,
+                remaining,
+                version,
+                false,
+            ),
+            None => (C::default(), ttl, 0, true),
+        };
+
+        let (result, delta) = update(&mut contents);
+        let Some(delta) = delta else {
+            return Ok((result, current));
+        };
+        let version = self.next_version();
+        let structure = contents.wrap();
+        if structure.is_empty() {
+            self.apply(Mutation::Delete {
                 key: key.to_string(),
+                version,
             });
+        } else {
+            let full = Mutation::Structure {
+                key: key.to_string(),
+                structure,
+                ttl,
+                version,
+            };
+            if created {
+                self.apply(full);
+            } else {
+                self.apply_as(
+                    full,
+                    Mutation::StructureDelta {
+                        key: key.to_string(),
+                        delta,
+                        previous: current,
+                        version,
+                    },
+                );
+            }
         }
+        Ok((result, version))
+    }
+
+    fn key_lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = hasher.finish() as usize % self.key_locks.len();
+        self.key_locks[stripe].lock().unwrap()
+    }
+
+    /// Returns the version of the latest write to `key`, including a delete,
+    /// or 0 if there is none.
+    fn current_version(&self, key: &str) -> u64 {
+        let entry = self.data.get(key).map_or(0, |entry| entry.version);
+        let tombstone = self
+            .tombstones
+            .get(key)
+            .map_or(0, |tombstone| tombstone.version);
+        entry.max(tombstone)
+    }
+
+    /// Returns whether a replicated put, delete or structure replaces what is
+    /// stored for its key: it has to be newer than anything stored, except
+    /// that a delete ends a counter whose lifetime began before it even if
+    /// the counter was updated since, as `merge_counter` does. A structure
+    /// delta applies only to the version it was made against; a replica that
+    /// missed an earlier update is left for anti-entropy or read repair.
+    fn supersedes(&self, mutation: &Mutation) -> bool {
+        if let Mutation::StructureDelta {
+            key,
+            previous,
+            version,
+            ..
+        } = mutation
+        {
+            return self
+                .data
+                .get(key)
+                .is_some_and(|entry| entry.version == *previous && entry.structure().is_some())
+                && self.is_newer(key, *version);
+        }
+        if let Mutation::Delete { key, version } = mutation {
+            let created = self
+                .data
+                .get(key)
+                .and_then(|entry| Some(entry.counter()?.created));
+            if let Some(created) = created {
+                return *version > created;
+            }
+        }
+        self.is_newer(mutation.key(), mutation.version())
+    }
+
+    /// Returns whether `version` is newer than anything stored for `key`,
+    /// including a tombstone.
+    fn is_newer(&self, key: &str, version: u64) -> bool {
+        if let Some(entry) = self.data.get(key) {
+            if entry.version >= version {
+                return false;
+            }
+        }
+        if let Some(tombstone) = self.tombstones.get(key) {
+            if tombstone.version >= version {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Hybrid logical clock: wall-clock microseconds, bumped past every version
+    /// seen so far so that versions stay monotonic on this node.
+    pub fn next_version(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let mut current = self.clock.load(Ordering::SeqCst);
+        loop {
+            let next = now.max(current + 1);
+            match self
+                .clock
+                .compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return next,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn observe_version(&self, version: u64) {
+        self.clock.fetch_max(version, Ordering::SeqCst);
+    }
+
+    /// Drops tombstones older than the configured tombstone TTL.
+    pub fn purge_tombstones(&self) {
+        let now = Instant::now();
+        self.tombstones
+            .retain(|_, tombstone| tombstone.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::monitoring::Monitoring;
+
+    #[test]
+    fn counters_diverged_under_one_version_get_a_new_one() {
+        let (events, _) = crossbeam::channel::unbounded();
+        let (replication, mut replicated) = tokio::sync::mpsc::unbounded_channel();
+        let cache = Cache::new(
+            Config::load(),
+            events,
+            replication,
+            Arc::new(Monitoring::new()),
+            "http://127.0.0.1:50051".to_string(),
+        );
+        let (_, version) = cache.increment("counter", 2, 0, None).unwrap();
+        while replicated.try_recv().is_ok() {}
+
+        // Another replica's concurrent increment, under the same version
+        let mut counter = PnCounter::new(0, 0);
+        counter.add("http://127.0.0.1:50052", 3).unwrap();
+        assert!(cache.apply_remote(Mutation::Counter {
+            key: "counter".to_string(),
+            counter,
+            ttl: None,
+            version,
+        }));
+
+        let Ok(Mutation::Counter {
+            counter,
+            version: merged,
+            ..
+        }) = replicated.try_recv()
+        else {
+            panic!("The merged counter was not replicated");
+        };
+        assert_eq!(counter.value(), 5);
+        assert!(merged > version);
+        assert_eq!(
+            cache.get_versioned("counter"),
+            Some((Bytes::from("5"), merged))
+        );
     }
 }
@@ -0,0 +1,237 @@
+// src/cluster_view.rs
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::anti_entropy::to_token_ranges;
+use crate::hashing::ConsistentHashing;
+use crate::proto::{ClusterMember, ClusterView, ClusterViewRequest};
+use crate::replication::Replicator;
+
+/// What this node last heard about a peer's membership view.
+struct PeerView {
+    epoch: u64,
+    digest: u64,
+    /// When the peer's digest started to differ from ours, if it does.
+    mismatch_since: Option<Instant>,
+}
+
+/// Compares this node's membership view with its peers'. Views can differ for
+/// a while because every node applies discovery events on its own; a view
+/// that still differs after one full check interval makes this node ask its
+/// discovery source for a fresh listing, which is the source of truth.
+pub struct ClusterViews {
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    resync: Arc<Notify>,
+    peers: Mutex<HashMap<String, PeerView>>,
+    interval: Duration,
+}
+
+impl ClusterViews {
+    pub fn new(
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        resync: Arc<Notify>,
+        config: &crate::config::Config,
+    ) -> Self {
+        Self {
+            hasher,
+            replicator,
+            resync,
+            peers: Mutex::new(HashMap::new()),
+            interval: Duration::from_secs(config.view_check_interval),
+        }
+    }
+
+    /// Describes this node's view, with the member list unless `summary`.
+    pub fn local_view(&self, summary: bool) -> ClusterView {
+        let ring = self.hasher.snapshot();
+        let members = if summary {
+            Vec::new()
+        } else {
+            ring.weights
+                .keys()
+                .map(|node| {
+                    let topology = ring.topology.get(node);
+                    ClusterMember {
+                        node: node.clone(),
+                        weight: ring.weight(node),
+                        zone: topology.and_then(|t| t.zone.clone()).unwrap_or_default(),
+                        host: topology.and_then(|t| t.host.clone()).unwrap_or_default(),
+                        alive: !ring.down.contains(node),
+                        ranges: to_token_ranges(&self.hasher.primary_ranges(&ring, node)),
+                    }
+                })
+                .collect()
+        };
+        ClusterView {
+            node: self.replicator.local_node().to_string(),
+            epoch: self.hasher.epoch(),
+            digest: self.hasher.digest(&ring),
+            members,
+        }
+    }
+
+    /// Records a peer's epoch and digest, as reported by the peer itself, and
+    /// brings this node's epoch up to the peer's.
+    pub fn observe(&self, peer: &str, epoch: u64, digest: u64) {
+        self.hasher.observe_epoch(epoch);
+        let local = self.hasher.digest(&self.hasher.snapshot());
+        let mut peers = self.peers.lock().unwrap();
+        let mismatch_since = match peers.get(peer) {
+            _ if digest == local => None,
+            Some(PeerView {
+                mismatch_since: Some(since),
+                ..
+            }) => Some(*since),
+            _ => Some(Instant::now()),
+        };
+        peers.insert(
+            peer.to_string(),
+            PeerView {
+                epoch,
+                digest,
+                mismatch_since,
+            },
+        );
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        if self.interval.is_zero() {
+            info!("Cluster view checks are disabled");
+            return;
+        }
+        let mut ticker = interval(self.interval);
+        loop {
+            ticker.tick().await;
+            let nodes = self.hasher.get_all_nodes();
+            self.peers
+                .lock()
+                .unwrap()
+                .retain(|peer, _| nodes.contains(peer));
+
+            let mut stale = Vec::new();
+            for peer in nodes {
+                if peer == self.replicator.local_node() {
+                    continue;
+                }
+                let Some(view) = self.fetch(&peer, true).await else {
+                    continue;
+                };
+                self.observe(&peer, view.epoch, view.digest);
+                let persistent = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(&peer)
+                    .and_then(|view| view.mismatch_since)
+                    .is_some_and(|since| since.elapsed() >= self.interval);
+                if persistent {
+                    stale.push(peer);
+                }
+            }
+
+            if let Some(peer) = stale.first() {
+                if let Some(view) = self.fetch(peer, false).await {
+                    self.log_difference(&view);
+                }
+                warn!(
+                    "Membership view differs from {} peers; resyncing discovery",
+                    stale.len()
+                );
+                self.resync.notify_one();
+            }
+        }
+    }
+
+    async fn fetch(&self, peer: &str, summary: bool) -> Option<ClusterView> {
+        let mut client = self.replicator.connect(peer).await?;
+        let local = self.local_view(true);
+        let request = ClusterViewRequest {
+            node: local.node,
+            epoch: local.epoch,
+            digest: local.digest,
+            summary,
+        };
+        match client
+            .get_cluster_view(self.replicator.peer_request(request))
+            .await
+        {
+            Ok(response) => Some(response.into_inner()),
+            Err(e) => {
+                warn!("Failed to fetch cluster view from node {}: {}", peer, e);
+                None
+            }
+        }
+    }
+
+    fn log_difference(&self, view: &ClusterView) {
+        let ring = self.hasher.snapshot();
+        let local: BTreeSet<&str> = ring.weights.keys().map(String::as_str).collect();
+        let remote: BTreeSet<&str> = view
+            .members
+            .iter()
+            .map(|member| member.node.as_str())
+            .collect();
+        warn!(
+            "Node {} (epoch {}) sees {:?} that this node does not, and misses {:?}",
+            view.node,
+            view.epoch,
+            remote.difference(&local).collect::<Vec<_>>(),
+            local.difference(&remote).collect::<Vec<_>>()
+        );
+    }
+
+    /// JSON for the `/nodes` route: this node's view and how peers compare.
+    pub fn to_json(&self) -> Value {
+        let view = self.local_view(false);
+        let members: Vec<Value> = view
+            .members
+            .iter()
+            .map(|member| {
+                let owned: f64 = member
+                    .ranges
+                    .iter()
+                    .map(|range| range.end.wrapping_sub(range.start) as f64)
+                    .sum();
+                json!({
+                    "node": member.node,
+                    "weight": member.weight,
+                    "zone": member.zone,
+                    "host": member.host,
+                    "alive": member.alive,
+                    "ranges": member.ranges.len(),
+                    "ownership": owned / u64::MAX as f64,
+                })
+            })
+            .collect();
+        let peers: Vec<Value> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(node, peer)| {
+                json!({
+                    "node": node,
+                    "epoch": peer.epoch,
+                    "digest": format!("{:016x}", peer.digest),
+                    "agrees": peer.digest == view.digest,
+                })
+            })
+            .collect();
+        json!({
+            "node": view.node,
+            "epoch": view.epoch,
+            "digest": format!("{:016x}", view.digest),
+            "members": members,
+            "peers": peers,
+        })
+    }
+}
@@ -2,6 +2,11 @@
 
 use serde::Deserialize;
 
+use crate::discovery::DiscoveryMode;
+use crate::hashing::HashAlgorithm;
+use crate::placement::PlacementAlgorithm;
+use crate::transaction_manager::{IsolationLevel, TransactionMode};
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub max_memory: usize,
@@ -9,28 +14,71 @@ pub struct Config {
     pub frequency_threshold: u64,
     pub replication_factor: usize,
     pub local_address: String,
+    pub node_id: Option<String>,
+    pub node_address: Option<String>,
     pub redis_url: String,
     pub enable_monitoring: bool,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    pub tls_ca_path: Option<String>,
+    pub tls_server_name: Option<String>,
     pub jwt_secret: Option<String>,
+    pub admin_token: Option<String>,
+    pub peer_token: Option<String>,
     pub transaction_timeout: u64,
     pub enable_transactions: bool,
+    pub transaction_isolation: IsolationLevel,
+    pub transaction_mode: TransactionMode,
+    pub max_transactions_per_client: usize,
+    pub transaction_log_path: String,
+    pub transaction_recovery_interval: u64,
+    pub in_doubt_timeout: u64,
+    pub lock_lease_ms: u64,
+    pub max_lock_lease_ms: u64,
+    pub tombstone_ttl: u64,
+    pub read_repair: bool,
+    pub anti_entropy_interval: u64,
+    pub anti_entropy_rate: u64,
+    pub hash_algorithm: HashAlgorithm,
+    pub placement: PlacementAlgorithm,
+    pub bounded_load_factor: f64,
+    pub weight_memory_unit: u64,
+    pub node_weight: f64,
+    pub min_zones: usize,
+    pub zone: Option<String>,
+    pub host_node: Option<String>,
+    pub discovery: DiscoveryMode,
+    pub static_peers: Vec<String>,
+    pub dns_name: Option<String>,
+    pub dns_interval: u64,
+    pub gossip_interval: u64,
+    pub gossip_timeout: u64,
+    pub gossip_indirect_probes: usize,
+    pub suspicion_timeout: u64,
+    pub view_check_interval: u64,
 }
 
 impl Config {
     pub fn load() -> Self {
         // Load from environment variables or a configuration file
         // For simplicity, we'll load from environment variables here
+        let max_memory: usize = std::env::var("MAX_MEMORY")
+            .unwrap_or_else(|_| "104857600".to_string())
+            .parse()
+            .unwrap();
+        let weight_memory_unit: u64 = std::env::var("WEIGHT_MEMORY_UNIT")
+            .unwrap_or_else(|_| "104857600".to_string())
+            .parse()
+            .unwrap();
+
         Self {
-            max_memory: std::env::var("MAX_MEMORY")
-                .unwrap_or_else(|_| "104857600".to_string())
-                .parse()
-                .unwrap(),
+            max_memory,
             default_ttl: std::env::var("DEFAULT_TTL")
                 .unwrap_or_else(|_| "3600".to_string())
                 .parse()
                 .unwrap(),
+            // Reads an entry needs before it leaves probation and is evicted
+            // by frequency instead of age; 1 disables probation
             frequency_threshold: std::env::var("FREQUENCY_THRESHOLD")
                 .unwrap_or_else(|_| "1".to_string())
                 .parse()
@@ -41,6 +89,12 @@ impl Config {
                 .unwrap(),
             local_address: std::env::var("LOCAL_ADDRESS")
                 .unwrap_or_else(|_| "0.0.0.0:50051".to_string()),
+            node_id: std::env::var("NODE_ID")
+                .or_else(|_| std::env::var("POD_NAME"))
+                .ok(),
+            node_address: std::env::var("NODE_ADDRESS")
+                .or_else(|_| std::env::var("POD_IP"))
+                .ok(),
             redis_url: std::env::var("REDIS_URL")
                 .unwrap_or_else(|_| "redis://127.0.0.1/".to_string()),
             enable_monitoring: std::env::var("ENABLE_MONITORING")
@@ -49,7 +103,16 @@ impl Config {
                 .unwrap(),
             tls_cert_path: std::env::var("TLS_CERT_PATH").ok(),
             tls_key_path: std::env::var("TLS_KEY_PATH").ok(),
+            tls_ca_path: std::env::var("TLS_CA_PATH").ok(),
+            // Name peer certificates are verified against, such as the headless
+            // service's DNS name; unset, each certificate must name the pod IP
+            tls_server_name: std::env::var("TLS_SERVER_NAME").ok(),
             jwt_secret: std::env::var("JWT_SECRET").ok(),
+            admin_token: std::env::var("ADMIN_TOKEN").ok(),
+            // Secret shared by the nodes, presented on every request between
+            // them in place of a client token; unset, those requests are
+            // authenticated like a client's and forwarded ones are routed again
+            peer_token: std::env::var("PEER_TOKEN").ok(),
             transaction_timeout: std::env::var("TRANSACTION_TIMEOUT")
                 .unwrap_or_else(|_| "30".to_string())
                 .parse()
@@ -58,6 +121,123 @@ impl Config {
                 .unwrap_or_else(|_| "true".to_string())
                 .parse()
                 .unwrap(),
+            transaction_isolation: std::env::var("TRANSACTION_ISOLATION")
+                .unwrap_or_else(|_| "read_committed".to_string())
+                .parse()
+                .unwrap(),
+            // "applied" makes uncommitted writes visible to everyone (dirty
+            // reads); see `TransactionMode::Applied`
+            transaction_mode: std::env::var("TRANSACTION_MODE")
+                .unwrap_or_else(|_| "buffered".to_string())
+                .parse()
+                .unwrap(),
+            max_transactions_per_client: std::env::var("MAX_TRANSACTIONS_PER_CLIENT")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse()
+                .unwrap(),
+            transaction_log_path: std::env::var("TRANSACTION_LOG_PATH")
+                .unwrap_or_else(|_| "transactions.log".to_string()),
+            // Seconds before a participant asks the coordinator about an
+            // in-doubt transaction, and between re-sending unacknowledged commits
+            transaction_recovery_interval: std::env::var("TRANSACTION_RECOVERY_INTERVAL")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap(),
+            // Seconds a participant holds the locks of an in-doubt transaction
+            // whose coordinator has left the cluster before presuming it
+            // aborted; 0 holds them until the coordinator answers
+            in_doubt_timeout: std::env::var("IN_DOUBT_TIMEOUT")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .unwrap(),
+            // Lease granted when a lock request does not ask for one, and the
+            // longest lease granted
+            lock_lease_ms: std::env::var("LOCK_LEASE_MS")
+                .unwrap_or_else(|_| "30000".to_string())
+                .parse()
+                .unwrap(),
+            max_lock_lease_ms: std::env::var("MAX_LOCK_LEASE_MS")
+                .unwrap_or_else(|_| "300000".to_string())
+                .parse()
+                .unwrap(),
+            tombstone_ttl: std::env::var("TOMBSTONE_TTL")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .unwrap(),
+            read_repair: std::env::var("READ_REPAIR")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap(),
+            anti_entropy_interval: std::env::var("ANTI_ENTROPY_INTERVAL")
+                .unwrap_or_else(|_| "600".to_string())
+                .parse()
+                .unwrap(),
+            anti_entropy_rate: std::env::var("ANTI_ENTROPY_RATE")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse()
+                .unwrap(),
+            // Changing it re-shards every key; see `HashAlgorithm`
+            hash_algorithm: std::env::var("HASH_ALGORITHM")
+                .unwrap_or_else(|_| "legacy".to_string())
+                .parse()
+                .unwrap(),
+            placement: std::env::var("PLACEMENT")
+                .unwrap_or_else(|_| "ring".to_string())
+                .parse()
+                .unwrap(),
+            bounded_load_factor: std::env::var("BOUNDED_LOAD_FACTOR")
+                .unwrap_or_else(|_| "0.25".to_string())
+                .parse()
+                .unwrap(),
+            weight_memory_unit,
+            // Defaults to the cache's memory budget, so that larger nodes own
+            // proportionally more keys
+            node_weight: std::env::var("NODE_WEIGHT")
+                .map(|weight| weight.parse().unwrap())
+                .unwrap_or(max_memory as f64 / weight_memory_unit as f64),
+            min_zones: std::env::var("MIN_ZONES")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .unwrap(),
+            zone: std::env::var("ZONE").ok(),
+            host_node: std::env::var("NODE_NAME").ok(),
+            discovery: std::env::var("DISCOVERY")
+                .unwrap_or_else(|_| "kubernetes".to_string())
+                .parse()
+                .unwrap(),
+            static_peers: std::env::var("STATIC_PEERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|peer| peer.trim().to_string())
+                .filter(|peer| !peer.is_empty())
+                .collect(),
+            dns_name: std::env::var("DNS_NAME").ok(),
+            dns_interval: std::env::var("DNS_INTERVAL")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .unwrap(),
+            // Milliseconds; 0 disables failure detection
+            gossip_interval: std::env::var("GOSSIP_INTERVAL_MS")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .unwrap(),
+            gossip_timeout: std::env::var("GOSSIP_TIMEOUT_MS")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse()
+                .unwrap(),
+            gossip_indirect_probes: std::env::var("GOSSIP_INDIRECT_PROBES")
+                .unwrap_or_else(|_| "3".to_string())
+                .parse()
+                .unwrap(),
+            suspicion_timeout: std::env::var("SUSPICION_TIMEOUT_MS")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse()
+                .unwrap(),
+            // Seconds between comparing membership views with peers; 0 disables
+            view_check_interval: std::env::var("VIEW_CHECK_INTERVAL")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .unwrap(),
         }
     }
 }
@@ -0,0 +1,165 @@
+// src/counter.rs
+
+use std::collections::BTreeMap;
+
+use tonic::Status;
+
+use crate::proto::CounterState;
+
+/// A PN-counter: every node keeps its own running totals of increments and
+/// decrements, and replicas merge by taking the larger total per node, so
+/// concurrent updates on different replicas are never lost.
+///
+/// `created` identifies the counter's lifetime: the version of the delete or
+/// plain value it replaced, or 0 if the key never existed. Replicas that
+/// create the counter concurrently agree on it, so all states with the same
+/// `created` merge, whichever replica made them. A delete newer than
+/// `created` ends the lifetime, and a counter created later replaces an
+/// older one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PnCounter {
+    pub created: u64,
+    pub initial: i64,
+    increments: BTreeMap<String, u64>,
+    decrements: BTreeMap<String, u64>,
+}
+
+impl PnCounter {
+    pub fn new(created: u64, initial: i64) -> Self {
+        Self {
+            created,
+            initial,
+            increments: BTreeMap::new(),
+            decrements: BTreeMap::new(),
+        }
+    }
+
+    /// The counter's value. Concurrent updates that each stayed in range can
+    /// still add up to more than an i64 holds; the value then saturates.
+    pub fn value(&self) -> i64 {
+        let increments: i128 = self.increments.values().map(|&n| n as i128).sum();
+        let decrements: i128 = self.decrements.values().map(|&n| n as i128).sum();
+        let value = self.initial as i128 + increments - decrements;
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Adds `delta` on behalf of `node` and returns the new value. Leaves the
+    /// counter unchanged if the value would overflow.
+    pub fn add(&mut self, node: &str, delta: i64) -> Result<i64, CounterError> {
+        let value = self
+            .value()
+            .checked_add(delta)
+            .ok_or(CounterError::Overflow)?;
+        let totals = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        let total = totals.get(node).copied().unwrap_or(0);
+        let total = total
+            .checked_add(delta.unsigned_abs())
+            .ok_or(CounterError::Overflow)?;
+        totals.insert(node.to_string(), total);
+        Ok(value)
+    }
+
+    /// Memory the per-node totals take.
+    pub fn size(&self) -> usize {
+        self.increments
+            .keys()
+            .chain(self.decrements.keys())
+            .map(|node| node.len() + std::mem::size_of::<u64>())
+            .sum()
+    }
+
+    /// Merges the state of another replica of the same counter. Replicas
+    /// that created it concurrently may disagree on the initial value; the
+    /// larger one is kept.
+    pub fn merge(&mut self, other: &PnCounter) {
+        self.initial = self.initial.max(other.initial);
+        for (node, &total) in &other.increments {
+            let local = self.increments.entry(node.clone()).or_insert(0);
+            *local = (*local).max(total);
+        }
+        for (node, &total) in &other.decrements {
+            let local = self.decrements.entry(node.clone()).or_insert(0);
+            *local = (*local).max(total);
+        }
+    }
+}
+
+impl From<&PnCounter> for CounterState {
+    fn from(counter: &PnCounter) -> Self {
+        Self {
+            created: counter.created,
+            initial: counter.initial,
+            increments: counter.increments.clone().into_iter().collect(),
+            decrements: counter.decrements.clone().into_iter().collect(),
+        }
+    }
+}
+
+impl From<CounterState> for PnCounter {
+    fn from(state: CounterState) -> Self {
+        Self {
+            created: state.created,
+            initial: state.initial,
+            increments: state.increments.into_iter().collect(),
+            decrements: state.decrements.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CounterError {
+    /// The update would take the value out of the i64 range.
+    Overflow,
+    /// The key holds a value that is not an integer.
+    NotACounter(String),
+    /// The key is locked by a prepared distributed transaction.
+    Locked(String),
+}
+
+impl std::fmt::Display for CounterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CounterError::Overflow => write!(f, "Counter would overflow"),
+            CounterError::NotACounter(key) => {
+                write!(f, "Key {} does not hold an integer", key)
+            }
+            CounterError::Locked(key) => {
+                write!(f, "Key {} is locked by a prepared transaction", key)
+            }
+        }
+    }
+}
+
+impl From<CounterError> for Status {
+    fn from(error: CounterError) -> Self {
+        match error {
+            CounterError::Overflow => Status::out_of_range(error.to_string()),
+            CounterError::NotACounter(_) => Status::failed_precondition(error.to_string()),
+            CounterError::Locked(_) => Status::aborted(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_updates_merge_through_the_wire_format() {
+        let mut a = PnCounter::new(0, 10);
+        let mut b = PnCounter::new(0, 10);
+        a.add("a", 5).unwrap();
+        b.add("b", -3).unwrap();
+        b.add("b", 1).unwrap();
+
+        let from_b = PnCounter::from(CounterState::from(&b));
+        a.merge(&from_b);
+        b.merge(&PnCounter::from(CounterState::from(&a)));
+        assert_eq!(a.value(), 13);
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,279 @@
+// src/discovery.rs
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hickory_resolver::TokioAsyncResolver;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::hashing::{ConsistentHashing, Topology};
+use crate::node_identity::NodeIdentity;
+use crate::pod_discovery::PodDiscovery;
+
+/// A source of cluster members. Implementations report what they see through
+/// `Membership`, which keeps the ring in line with it.
+#[async_trait]
+pub trait Discovery: Send {
+    fn name(&self) -> &'static str;
+
+    /// Feeds membership changes into the ring until the source ends.
+    async fn run(self: Box<Self>, membership: Membership);
+}
+
+/// Selects a `Discovery` in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    Kubernetes,
+    Static,
+    Dns,
+}
+
+impl DiscoveryMode {
+    pub fn build(self, config: &Config) -> Box<dyn Discovery> {
+        match self {
+            DiscoveryMode::Kubernetes => Box::new(PodDiscovery::new(config.weight_memory_unit)),
+            DiscoveryMode::Static => Box::new(StaticDiscovery {
+                peers: config.static_peers.clone(),
+            }),
+            DiscoveryMode::Dns => Box::new(DnsDiscovery {
+                name: config.dns_name.clone(),
+                interval: Duration::from_secs(config.dns_interval.max(1)),
+            }),
+        }
+    }
+}
+
+impl FromStr for DiscoveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "kubernetes" | "k8s" => Ok(DiscoveryMode::Kubernetes),
+            "static" => Ok(DiscoveryMode::Static),
+            "dns" => Ok(DiscoveryMode::Dns),
+            other => Err(format!("Unknown discovery mode: {}", other)),
+        }
+    }
+}
+
+/// The members a discovery source has added to the ring, by the name the
+/// source knows them under. This node is never added or removed here: it
+/// joins the ring at startup with its configured weight.
+///
+/// Every node advertises its own weight through gossip, and that is the
+/// weight all nodes use for it. The weight discovery reports for a peer is
+/// only a first estimate, used until the peer has advertised its own; one
+/// reported for this node replaces its weight, which then spreads to peers.
+pub struct Membership {
+    hasher: Arc<ConsistentHashing>,
+    identity: NodeIdentity,
+    members: HashMap<String, String>,
+    resync: Arc<Notify>,
+}
+
+impl Membership {
+    pub fn new(
+        hasher: Arc<ConsistentHashing>,
+        identity: NodeIdentity,
+        resync: Arc<Notify>,
+    ) -> Self {
+        Self {
+            hasher,
+            identity,
+            members: HashMap::new(),
+            resync,
+        }
+    }
+
+    pub fn identity(&self) -> &NodeIdentity {
+        &self.identity
+    }
+
+    /// Notified when this node's view has drifted from its peers' and the
+    /// source should list all members again instead of waiting for changes.
+    pub fn resync(&self) -> Arc<Notify> {
+        self.resync.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Adds a member or updates its endpoint and topology. `weight` is what
+    /// the source knows of the member's weight, if anything.
+    pub fn join(&mut self, name: &str, endpoint: String, weight: Option<f64>, topology: Topology) {
+        if self.identity.is_self(&endpoint) {
+            // Only fill in the failure domains the configuration left unset
+            let topology = Topology {
+                zone: self.identity.topology.zone.clone().or(topology.zone),
+                host: self.identity.topology.host.clone().or(topology.host),
+            };
+            self.hasher.set_member(endpoint, topology, weight);
+            return;
+        }
+
+        match self.members.insert(name.to_string(), endpoint.clone()) {
+            Some(previous) if previous == endpoint => {
+                self.hasher.set_member(endpoint, topology, None);
+            }
+            previous => {
+                // Until the member advertises its own weight
+                let weight = weight.unwrap_or(self.identity.weight);
+                info!(
+                    "Member {} joined as {} (weight {}, zone {:?}, host {:?})",
+                    name, endpoint, weight, topology.zone, topology.host
+                );
+                self.hasher
+                    .replace_member(previous.as_deref(), endpoint, topology, Some(weight));
+            }
+        }
+    }
+
+    pub fn leave(&mut self, name: &str) {
+        if let Some(endpoint) = self.members.remove(name) {
+            self.hasher.remove_node(&endpoint);
+            info!("Member {} left ({})", name, endpoint);
+        }
+    }
+
+    /// Removes every member not in `names`, after a full listing.
+    pub fn retain(&mut self, names: &HashSet<String>) {
+        let vanished: Vec<String> = self
+            .members
+            .keys()
+            .filter(|name| !names.contains(*name))
+            .cloned()
+            .collect();
+        for name in vanished {
+            self.leave(&name);
+        }
+    }
+}
+
+/// A fixed list of peers from `STATIC_PEERS`, as `host:port` or full
+/// endpoints. Peers join with no topology and this node's weight until they
+/// advertise their own. The list
+/// cannot change, so there is nothing to resync.
+pub struct StaticDiscovery {
+    peers: Vec<String>,
+}
+
+#[async_trait]
+impl Discovery for StaticDiscovery {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    async fn run(self: Box<Self>, mut membership: Membership) {
+        for peer in &self.peers {
+            let endpoint = if peer.contains("://") {
+                peer.clone()
+            } else {
+                match peer.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => membership
+                        .identity()
+                        .peer_endpoint(host.trim_matches(['[', ']']), port.parse().ok()),
+                    _ => membership.identity().peer_endpoint(peer, None),
+                }
+            };
+            membership.join(peer, endpoint, None, Topology::default());
+        }
+        info!("Joined {} static peers", membership.len());
+    }
+}
+
+/// Periodically resolves `DNS_NAME`, such as a headless service. Names that
+/// start with `_` are looked up as SRV records, which also give each peer's
+/// port; other names as A/AAAA records, with peers on this node's port.
+pub struct DnsDiscovery {
+    name: Option<String>,
+    interval: Duration,
+}
+
+impl DnsDiscovery {
+    async fn resolve(
+        resolver: &TokioAsyncResolver,
+        name: &str,
+        identity: &NodeIdentity,
+    ) -> Option<HashSet<String>> {
+        let mut endpoints = HashSet::new();
+        if name.starts_with('_') {
+            let records = match resolver.srv_lookup(name).await {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!("SRV lookup of {} failed: {}", name, e);
+                    return None;
+                }
+            };
+            for record in records.iter() {
+                let target = record.target().to_utf8();
+                match resolver.lookup_ip(target.as_str()).await {
+                    Ok(ips) => endpoints
+                        .extend(ips.iter().map(|ip| {
+                            identity.peer_endpoint(&ip.to_string(), Some(record.port()))
+                        })),
+                    Err(e) => warn!("Lookup of SRV target {} failed: {}", target, e),
+                }
+            }
+        } else {
+            match resolver.lookup_ip(name).await {
+                Ok(ips) => endpoints.extend(
+                    ips.iter()
+                        .map(|ip| identity.peer_endpoint(&ip.to_string(), None)),
+                ),
+                Err(e) => {
+                    warn!("Lookup of {} failed: {}", name, e);
+                    return None;
+                }
+            }
+        }
+        Some(endpoints)
+    }
+}
+
+#[async_trait]
+impl Discovery for DnsDiscovery {
+    fn name(&self) -> &'static str {
+        "dns"
+    }
+
+    async fn run(self: Box<Self>, mut membership: Membership) {
+        let Some(name) = &self.name else {
+            error!("DNS discovery needs DNS_NAME to be set");
+            return;
+        };
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!("Failed to create DNS resolver: {}", e);
+                return;
+            }
+        };
+
+        let resync = membership.resync();
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = resync.notified() => {}
+            }
+            // A failed lookup keeps the current members rather than emptying
+            // the ring
+            let Some(endpoints) = Self::resolve(&resolver, name, membership.identity()).await
+            else {
+                continue;
+            };
+            membership.retain(&endpoints);
+            for endpoint in endpoints {
+                membership.join(&endpoint.clone(), endpoint, None, Topology::default());
+            }
+        }
+    }
+}
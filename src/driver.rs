@@ -115,9 +115,7 @@ impl CacheDriver {
     }
 
     pub async fn begin_transaction(&self) -> Option<String> {
-        let request = Request::new(TransactionRequest {
-            transaction_id: String::new(),
-        });
+        let request = Request::new(TransactionRequest::default());
         if let Ok(response) = self.client.clone().begin_transaction(request).await {
             let transaction_response = response.into_inner();
             if transaction_response.success {
@@ -128,7 +126,10 @@ impl CacheDriver {
     }
 
     pub async fn commit_transaction(&self, transaction_id: String) -> bool {
-        let request = Request::new(TransactionRequest { transaction_id });
+        let request = Request::new(TransactionRequest {
+            transaction_id,
+            ..Default::default()
+        });
         if let Ok(response) = self.client.clone().commit_transaction(request).await {
             return response.into_inner().success;
         }
@@ -136,7 +137,10 @@ impl CacheDriver {
     }
 
     pub async fn rollback_transaction(&self, transaction_id: String) -> bool {
-        let request = Request::new(TransactionRequest { transaction_id });
+        let request = Request::new(TransactionRequest {
+            transaction_id,
+            ..Default::default()
+        });
         if let Ok(response) = self.client.clone().rollback_transaction(request).await {
             return response.into_inner().success;
         }
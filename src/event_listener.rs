@@ -5,6 +5,8 @@ pub enum EventType {
     Put,
     Evict,
     Expire,
+    /// A lock was released by its holder or its lease expired.
+    LockRelease,
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,427 @@
+// src/gossip.rs
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use rand::seq::SliceRandom;
+use tokio::time::{interval, timeout};
+use tracing::{info, warn};
+
+use crate::hashing::ConsistentHashing;
+use crate::monitoring::Monitoring;
+use crate::proto::{MemberState, MemberUpdate, PingReqRequest, PingRequest, PingResponse};
+use crate::replication::Replicator;
+
+/// Most membership updates piggybacked on one message.
+const MAX_PIGGYBACK: usize = 8;
+/// Each update is piggybacked `RETRANSMIT_MULTIPLIER * ceil(log2(members + 1))`
+/// times, enough to reach every member with high probability.
+const RETRANSMIT_MULTIPLIER: u32 = 3;
+/// A dead member is pinged once every this many rounds, so that it can learn
+/// it was declared dead and refute it once it is reachable again.
+const DEAD_PROBE_ROUNDS: u64 = 10;
+
+struct Member {
+    state: MemberState,
+    incarnation: u64,
+    since: Instant,
+}
+
+/// SWIM failure detector. Every round this node pings one member; if it does
+/// not answer, `indirect_probes` other members ping it on our behalf, and if
+/// none of them gets an answer either the member becomes suspect. Suspects
+/// that do not refute the suspicion within `suspicion_timeout` are declared
+/// dead and taken out of placement until they refute it. Updates spread by
+/// piggybacking on pings, and a member refutes a suspicion by gossiping itself
+/// alive with a higher incarnation. Every ping and ack also carries the
+/// sender's own ring weight, which is the weight every node uses for it.
+pub struct Gossip {
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    monitoring: Arc<Monitoring>,
+    local_node_address: String,
+    incarnation: AtomicU64,
+    members: Mutex<HashMap<String, Member>>,
+    updates: Mutex<Vec<(MemberUpdate, u32)>>,
+    probe_order: Mutex<Vec<String>>,
+    interval: Duration,
+    timeout: Duration,
+    indirect_probes: usize,
+    suspicion_timeout: Duration,
+}
+
+impl Gossip {
+    pub fn new(
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        monitoring: Arc<Monitoring>,
+        local_node_address: String,
+        config: &crate::config::Config,
+    ) -> Self {
+        Self {
+            hasher,
+            replicator,
+            monitoring,
+            local_node_address,
+            incarnation: AtomicU64::new(0),
+            members: Mutex::new(HashMap::new()),
+            updates: Mutex::new(Vec::new()),
+            probe_order: Mutex::new(Vec::new()),
+            interval: Duration::from_millis(config.gossip_interval),
+            timeout: Duration::from_millis(config.gossip_timeout),
+            indirect_probes: config.gossip_indirect_probes,
+            suspicion_timeout: Duration::from_millis(config.suspicion_timeout),
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        if self.interval.is_zero() {
+            info!("Gossip failure detection is disabled");
+            return;
+        }
+        let mut ticker = interval(self.interval);
+        let mut round = 0u64;
+        loop {
+            ticker.tick().await;
+            round += 1;
+            self.sync_members();
+            self.expire_suspects();
+            if let Some(target) = self.next_target() {
+                self.probe(&target).await;
+            }
+            if round.is_multiple_of(DEAD_PROBE_ROUNDS) {
+                if let Some(dead) = self.random_dead() {
+                    self.ping(&dead).await;
+                }
+            }
+        }
+    }
+
+    /// Answers a ping from a peer.
+    /// A sender this node holds suspect or dead is told so, so that it can
+    /// refute it.
+    pub fn handle_ping(&self, request: PingRequest) -> PingResponse {
+        self.adopt_weight(&request.from, request.weight);
+        self.apply_updates(request.updates);
+        PingResponse {
+            ack: true,
+            updates: self.piggyback_for(&request.from),
+            weight: self.own_weight(),
+        }
+    }
+
+    /// Pings `target` on behalf of a peer whose direct ping went unanswered.
+    pub async fn handle_ping_req(&self, request: PingReqRequest) -> PingResponse {
+        self.apply_updates(request.updates);
+        let ack = self.ping(&request.target).await;
+        PingResponse {
+            ack,
+            updates: self.piggyback(),
+            weight: self.own_weight(),
+        }
+    }
+
+    fn own_weight(&self) -> f64 {
+        self.hasher
+            .weight_of(&self.local_node_address)
+            .unwrap_or(1.0)
+    }
+
+    /// Takes the weight a member advertised for itself. Members discovery has
+    /// not added, or has removed, are left out of the ring.
+    fn adopt_weight(&self, node: &str, weight: f64) {
+        if weight > 0.0 && node != self.local_node_address {
+            self.hasher.update_weight(node, weight);
+        }
+    }
+
+    /// Tracks every ring member, including those that are down, and forgets
+    /// members discovery removed.
+    fn sync_members(&self) {
+        let ring = self.hasher.members();
+        let mut members = self.members.lock().unwrap();
+        members.retain(|node, _| ring.contains(node));
+        for node in ring {
+            if node == self.local_node_address {
+                continue;
+            }
+            members.entry(node).or_insert_with(|| Member {
+                state: MemberState::Alive,
+                incarnation: 0,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Members are probed in a shuffled round-robin order, so every member is
+    /// probed once per `members` rounds.
+    fn next_target(&self) -> Option<String> {
+        let mut order = self.probe_order.lock().unwrap();
+        let members = self.members.lock().unwrap();
+        loop {
+            if order.is_empty() {
+                order.extend(
+                    members
+                        .iter()
+                        .filter(|(_, member)| member.state != MemberState::Dead)
+                        .map(|(node, _)| node.clone()),
+                );
+                if order.is_empty() {
+                    return None;
+                }
+                order.shuffle(&mut rand::thread_rng());
+            }
+            let node = order.pop()?;
+            if members
+                .get(&node)
+                .is_some_and(|member| member.state != MemberState::Dead)
+            {
+                return Some(node);
+            }
+        }
+    }
+
+    async fn probe(&self, target: &str) {
+        if self.ping(target).await {
+            return;
+        }
+
+        let mut helpers: Vec<String> = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(node, member)| *node != target && member.state == MemberState::Alive)
+            .map(|(node, _)| node.clone())
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(self.indirect_probes);
+
+        let acks = join_all(helpers.iter().map(|helper| self.ping_req(helper, target))).await;
+        if acks.into_iter().any(|ack| ack) {
+            return;
+        }
+
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get(target) {
+            if member.state == MemberState::Alive {
+                let incarnation = member.incarnation;
+                self.transition(&mut members, target, MemberState::Suspect, incarnation);
+            }
+        }
+    }
+
+    async fn ping(&self, node: &str) -> bool {
+        let request = PingRequest {
+            from: self.local_node_address.clone(),
+            updates: self.piggyback_for(node),
+            weight: self.own_weight(),
+        };
+        let response = timeout(self.timeout, async {
+            let mut client = self.replicator.connect(node).await?;
+            client
+                .ping(self.replicator.peer_request(request))
+                .await
+                .ok()
+        })
+        .await;
+        match response {
+            Ok(Some(response)) => {
+                let response = response.into_inner();
+                self.adopt_weight(node, response.weight);
+                self.apply_updates(response.updates);
+                response.ack
+            }
+            _ => false,
+        }
+    }
+
+    async fn ping_req(&self, helper: &str, target: &str) -> bool {
+        let request = PingReqRequest {
+            from: self.local_node_address.clone(),
+            target: target.to_string(),
+            updates: self.piggyback(),
+        };
+        // The helper needs a full timeout of its own to ping the target
+        let response = timeout(self.timeout * 2, async {
+            let mut client = self.replicator.connect(helper).await?;
+            client
+                .ping_req(self.replicator.peer_request(request))
+                .await
+                .ok()
+        })
+        .await;
+        match response {
+            Ok(Some(response)) => {
+                let response = response.into_inner();
+                self.adopt_weight(helper, response.weight);
+                self.apply_updates(response.updates);
+                response.ack
+            }
+            _ => false,
+        }
+    }
+
+    fn expire_suspects(&self) {
+        let mut members = self.members.lock().unwrap();
+        let expired: Vec<(String, u64)> = members
+            .iter()
+            .filter(|(_, member)| {
+                member.state == MemberState::Suspect
+                    && member.since.elapsed() >= self.suspicion_timeout
+            })
+            .map(|(node, member)| (node.clone(), member.incarnation))
+            .collect();
+        for (node, incarnation) in expired {
+            self.transition(&mut members, &node, MemberState::Dead, incarnation);
+        }
+    }
+
+    fn apply_updates(&self, updates: Vec<MemberUpdate>) {
+        for update in updates {
+            self.apply(update);
+        }
+    }
+
+    /// Applies an update if it supersedes what this node knows: a higher
+    /// incarnation always wins, and at the same incarnation dead overrides
+    /// suspect, which overrides alive.
+    fn apply(&self, update: MemberUpdate) {
+        let state = update.state();
+        if update.node == self.local_node_address {
+            let current = self.incarnation.load(Ordering::SeqCst);
+            if state != MemberState::Alive && update.incarnation >= current {
+                let incarnation = update.incarnation + 1;
+                self.incarnation.fetch_max(incarnation, Ordering::SeqCst);
+                warn!(
+                    "Refuting {:?} rumour about this node with incarnation {}",
+                    state, incarnation
+                );
+                let members = self.members.lock().unwrap().len();
+                self.enqueue(
+                    MemberUpdate {
+                        node: self.local_node_address.clone(),
+                        state: MemberState::Alive as i32,
+                        incarnation,
+                    },
+                    members,
+                );
+            }
+            return;
+        }
+
+        let mut members = self.members.lock().unwrap();
+        let Some(member) = members.get(&update.node) else {
+            return;
+        };
+        let supersedes = match (state, member.state) {
+            (MemberState::Alive, _) => update.incarnation > member.incarnation,
+            (MemberState::Suspect, MemberState::Alive) => update.incarnation >= member.incarnation,
+            (MemberState::Suspect, _) => update.incarnation > member.incarnation,
+            (MemberState::Dead, MemberState::Dead) => false,
+            (MemberState::Dead, _) => update.incarnation >= member.incarnation,
+        };
+        if supersedes {
+            self.transition(&mut members, &update.node, state, update.incarnation);
+        }
+    }
+
+    fn transition(
+        &self,
+        members: &mut HashMap<String, Member>,
+        node: &str,
+        state: MemberState,
+        incarnation: u64,
+    ) {
+        let Some(member) = members.get_mut(node) else {
+            return;
+        };
+        let previous = member.state;
+        member.state = state;
+        member.incarnation = incarnation;
+        member.since = Instant::now();
+
+        if state != previous {
+            info!(
+                "Member {} is now {:?} (incarnation {})",
+                node, state, incarnation
+            );
+            self.monitoring
+                .member_transitions
+                .with_label_values(&[state.as_str_name()])
+                .inc();
+            if state == MemberState::Dead {
+                self.hasher.set_alive(node, false);
+            } else if previous == MemberState::Dead {
+                self.hasher.set_alive(node, true);
+            }
+        }
+
+        let count = members.len();
+        self.enqueue(
+            MemberUpdate {
+                node: node.to_string(),
+                state: state as i32,
+                incarnation,
+            },
+            count,
+        );
+    }
+
+    fn enqueue(&self, update: MemberUpdate, members: usize) {
+        let transmissions =
+            RETRANSMIT_MULTIPLIER * (members as u32 + 1).next_power_of_two().ilog2().max(1);
+        let mut updates = self.updates.lock().unwrap();
+        updates.retain(|(existing, _)| existing.node != update.node);
+        updates.push((update, transmissions));
+    }
+
+    /// Picks one of the dead members at random.
+    fn random_dead(&self) -> Option<String> {
+        let members = self.members.lock().unwrap();
+        let dead: Vec<&String> = members
+            .iter()
+            .filter(|(_, member)| member.state == MemberState::Dead)
+            .map(|(node, _)| node)
+            .collect();
+        dead.choose(&mut rand::thread_rng())
+            .map(|node| node.to_string())
+    }
+
+    /// Like `piggyback`, adding what this node believes about `node` when it
+    /// is not alive, which the retransmissions may have stopped carrying.
+    fn piggyback_for(&self, node: &str) -> Vec<MemberUpdate> {
+        let mut updates = self.piggyback();
+        let rumour = self.members.lock().unwrap().get(node).and_then(|member| {
+            (member.state != MemberState::Alive).then(|| MemberUpdate {
+                node: node.to_string(),
+                state: member.state as i32,
+                incarnation: member.incarnation,
+            })
+        });
+        if let Some(rumour) = rumour {
+            updates.retain(|update| update.node != node);
+            updates.push(rumour);
+        }
+        updates
+    }
+
+    /// Takes the updates to send with the next message, least-sent first.
+    fn piggyback(&self) -> Vec<MemberUpdate> {
+        let mut updates = self.updates.lock().unwrap();
+        updates.sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+        let taken: Vec<MemberUpdate> = updates
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(update, remaining)| {
+                *remaining -= 1;
+                update.clone()
+            })
+            .collect();
+        updates.retain(|(_, remaining)| *remaining > 0);
+        taken
+    }
+}
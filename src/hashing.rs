@@ -1,80 +1,595 @@
 // src/hashing.rs
 
-use std::collections::BTreeMap;
-use std::hash::{Hash, Hasher};
-use std::sync::RwLock;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::Hasher;
+use std::str::FromStr;
+use std::sync::{RwLock, RwLockWriteGuard};
 
+use serde::Deserialize;
 use std::collections::hash_map::DefaultHasher;
+use tokio::sync::watch;
+
+use crate::placement::Placement;
+
+/// Hash function used to place keys and virtual nodes on the ring.
+///
+/// Every node in a cluster, and every smart client that computes placement
+/// itself, must use the same algorithm. The stable algorithms hash the UTF-8
+/// bytes of the key with seed 0:
+///
+/// | Algorithm  | Definition                                   | `""`                 | `"a"`                |
+/// | ---------- | -------------------------------------------- | -------------------- | -------------------- |
+/// | `xxhash64` | XXH64                                        | `0xef46db3751d8e999` | `0xd24ec4f1a98c6e5b` |
+/// | `murmur3`  | low 64 bits (h1) of MurmurHash3_x64_128      | `0x0000000000000000` | `0x85555565f6597889` |
+///
+/// `legacy` is the standard library's `DefaultHasher`, which is not stable
+/// across Rust releases. It is the default because it is what clusters built
+/// before the stable algorithms used: changing the algorithm re-shards every
+/// key, and nodes refuse peers that use another one, so a cluster moves to a
+/// stable algorithm with a full restart rather than a rolling upgrade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    XxHash64,
+    Murmur3,
+    Legacy,
+}
+
+impl HashAlgorithm {
+    pub fn hash(&self, bytes: &[u8]) -> u64 {
+        match self {
+            HashAlgorithm::XxHash64 => xxhash_rust::xxh64::xxh64(bytes, 0),
+            HashAlgorithm::Murmur3 => {
+                murmur3::murmur3_x64_128(&mut std::io::Cursor::new(bytes), 0).unwrap() as u64
+            }
+            HashAlgorithm::Legacy => {
+                // Matches `str::hash`, which appends 0xff after the bytes
+                let mut hasher = DefaultHasher::new();
+                hasher.write(bytes);
+                hasher.write_u8(0xff);
+                hasher.finish()
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::XxHash64 => "xxhash64",
+            HashAlgorithm::Murmur3 => "murmur3",
+            HashAlgorithm::Legacy => "legacy",
+        }
+    }
+
+    /// Hash of a fixed probe string, exchanged with peers so that two nodes
+    /// only talk to each other if they place keys identically.
+    pub fn fingerprint(&self) -> u64 {
+        self.hash(b"distributed-cache")
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xxhash64" | "xxh64" => Ok(HashAlgorithm::XxHash64),
+            "murmur3" => Ok(HashAlgorithm::Murmur3),
+            "legacy" => Ok(HashAlgorithm::Legacy),
+            other => Err(format!("Unknown hash algorithm: {}", other)),
+        }
+    }
+}
+
+/// Failure domains of a member: its availability zone and the Kubernetes
+/// node (host) it runs on. Unknown domains never conflict with each other.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub zone: Option<String>,
+    pub host: Option<String>,
+}
+
+/// Ring membership: the virtual-node tokens, the sorted list of members and
+/// each member's weight and topology. Members in `down` keep their weight and
+/// topology but have no tokens and are not in `nodes`, so they own no keys.
+#[derive(Debug, Clone, Default)]
+pub struct Ring {
+    pub tokens: BTreeMap<u64, String>,
+    pub nodes: Vec<String>,
+    pub weights: BTreeMap<String, f64>,
+    pub topology: BTreeMap<String, Topology>,
+    pub down: BTreeSet<String>,
+    /// Incremented by every change, so that placements can cache what they
+    /// derive from a ring.
+    pub generation: u64,
+    /// Membership epoch: a Lamport clock that advances when a node joins,
+    /// leaves or moves, and catches up with the epochs peers report.
+    pub epoch: u64,
+}
+
+impl Ring {
+    pub fn weight(&self, node: &str) -> f64 {
+        self.weights.get(node).copied().unwrap_or(1.0)
+    }
+
+    pub fn zone(&self, node: &str) -> Option<&str> {
+        self.topology.get(node)?.zone.as_deref()
+    }
+
+    fn host(&self, node: &str) -> Option<&str> {
+        self.topology.get(node)?.host.as_deref()
+    }
+
+    /// Picks `n` members from `candidates`, which are in placement order,
+    /// spreading them across failure domains: first members in distinct
+    /// zones until `min_zones` zones are covered, then members on distinct
+    /// hosts, then whatever is left. The result keeps placement order, so the
+    /// primary is unchanged.
+    pub fn spread(&self, candidates: Vec<String>, n: usize, min_zones: usize) -> Vec<String> {
+        let mut chosen = vec![false; candidates.len()];
+        let mut zones = HashSet::new();
+        let mut hosts = HashSet::new();
+        let mut count = 0;
+
+        for pass in 0..3 {
+            for (i, node) in candidates.iter().enumerate() {
+                if count >= n {
+                    break;
+                }
+                if chosen[i] {
+                    continue;
+                }
+                let zone = self.zone(node);
+                let host = self.host(node);
+                let eligible = match pass {
+                    0 => {
+                        i == 0
+                            || (zones.len() < min_zones && zone.is_some_and(|z| !zones.contains(z)))
+                    }
+                    1 => host.is_none_or(|h| !hosts.contains(h)),
+                    _ => true,
+                };
+                if eligible {
+                    chosen[i] = true;
+                    count += 1;
+                    zones.extend(zone);
+                    hosts.extend(host);
+                }
+            }
+        }
+
+        candidates
+            .into_iter()
+            .zip(chosen)
+            .filter_map(|(node, chosen)| chosen.then_some(node))
+            .collect()
+    }
+
+    /// Returns the first `n` distinct nodes clockwise from `token`.
+    pub fn walk(&self, token: u64, n: usize) -> Vec<String> {
+        let mut nodes = Vec::new();
+        let iter = self.tokens.range(token..).chain(self.tokens.iter());
+
+        for (_, node) in iter {
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+            if nodes.len() >= n {
+                break;
+            }
+        }
+
+        nodes
+    }
+}
 
 pub struct ConsistentHashing {
-    ring: RwLock<BTreeMap<u64, String>>,
+    ring: RwLock<Ring>,
     replicas: usize,
+    algorithm: HashAlgorithm,
+    placement: Box<dyn Placement>,
+    min_zones: usize,
+    changes: watch::Sender<u64>,
 }
 
 impl ConsistentHashing {
-    pub fn new(replicas: usize) -> Self {
+    pub fn new(
+        replicas: usize,
+        algorithm: HashAlgorithm,
+        placement: Box<dyn Placement>,
+        min_zones: usize,
+    ) -> Self {
+        let (changes, _) = watch::channel(0);
         Self {
-            ring: RwLock::new(BTreeMap::new()),
+            ring: RwLock::new(Ring::default()),
             replicas,
+            algorithm,
+            placement,
+            min_zones,
+            changes,
         }
     }
 
-    pub fn add_node(&self, node: String) {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn placement(&self) -> &dyn Placement {
+        self.placement.as_ref()
+    }
+
+    /// Adds a node, or changes the weight of an existing one. A node gets
+    /// `replicas * weight` virtual nodes, numbered from 0, so changing the weight
+    /// only adds or removes the highest-numbered ones and moves no other keys.
+    pub fn set_weight(&self, node: String, weight: f64) {
+        let mut ring = self.ring.write().unwrap();
+        if !ring.weights.contains_key(&node) {
+            ring.epoch += 1;
+        }
+        if self.weigh(&mut ring, node, weight) {
+            self.changed(ring);
+        }
+    }
+
+    /// Changes the weight of a node already in the ring, up or down, and
+    /// ignores nodes that are not.
+    pub fn update_weight(&self, node: &str, weight: f64) {
         let mut ring = self.ring.write().unwrap();
-        for i in 0..self.replicas {
-            let virtual_node = format!("{}-{}", node, i);
-            let hash = self.hash(&virtual_node);
-            ring.insert(hash, node.clone());
+        if ring.weights.contains_key(node) && self.weigh(&mut ring, node.to_string(), weight) {
+            self.changed(ring);
         }
     }
 
+    /// Records the failure domains of a node and, if given, sets its weight,
+    /// with a single notification for both.
+    pub fn set_member(&self, node: String, topology: Topology, weight: Option<f64>) {
+        self.replace_member(None, node, topology, weight);
+    }
+
+    /// Like `set_member`, first removing `previous`, the node's old endpoint.
+    pub fn replace_member(
+        &self,
+        previous: Option<&str>,
+        node: String,
+        topology: Topology,
+        weight: Option<f64>,
+    ) {
+        let mut ring = self.ring.write().unwrap();
+        let mut changed = false;
+        if let Some(previous) = previous {
+            changed |= ring.weights.contains_key(previous);
+            self.remove(&mut ring, previous);
+        }
+        changed |= !ring.weights.contains_key(&node) && weight.is_some();
+        changed |= ring.topology.get(&node) != Some(&topology);
+        if changed {
+            ring.epoch += 1;
+        }
+        ring.topology.insert(node.clone(), topology);
+        if let Some(weight) = weight {
+            changed |= self.weigh(&mut ring, node, weight);
+        }
+        if changed {
+            self.changed(ring);
+        }
+    }
+
+    pub fn weight_of(&self, node: &str) -> Option<f64> {
+        self.ring.read().unwrap().weights.get(node).copied()
+    }
+
+    /// Adds or reweighs a node. Returns whether placement changed.
+    fn weigh(&self, ring: &mut Ring, node: String, weight: f64) -> bool {
+        let before = ring
+            .weights
+            .get(&node)
+            .map_or(0, |weight| self.virtual_nodes(*weight));
+        let after = self.virtual_nodes(weight);
+        let is_new = !ring.weights.contains_key(&node);
+        if !is_new && before == after && ring.weight(&node) == weight {
+            return false;
+        }
+        if ring.down.contains(&node) {
+            // Takes effect when the node comes back up
+            ring.weights.insert(node, weight);
+            return false;
+        }
+
+        if let Err(index) = ring.nodes.binary_search(&node) {
+            ring.nodes.insert(index, node.clone());
+        }
+        ring.weights.insert(node.clone(), weight);
+        for i in after..before {
+            let hash = self.virtual_node_token(&node, i);
+            ring.tokens.remove(&hash);
+        }
+        for i in before..after {
+            let hash = self.virtual_node_token(&node, i);
+            ring.tokens.insert(hash, node.clone());
+        }
+        true
+    }
+
+    /// Records the failure domains of a node. Replicas move if they change.
+    pub fn set_topology(&self, node: String, topology: Topology) {
+        self.set_member(node, topology, None);
+    }
+
+    pub fn zone_of(&self, node: &str) -> Option<String> {
+        self.ring.read().unwrap().zone(node).map(str::to_string)
+    }
+
+    /// Orders nodes so that those in `zone` come first, keeping the order
+    /// within each group.
+    pub fn by_proximity(&self, mut nodes: Vec<String>, zone: Option<&str>) -> Vec<String> {
+        if let Some(zone) = zone {
+            let ring = self.ring.read().unwrap();
+            nodes.sort_by_key(|node| ring.zone(node) != Some(zone));
+        }
+        nodes
+    }
+
+    /// Takes a member out of placement while it is down, or puts it back,
+    /// keeping its weight and topology either way. Unknown nodes are ignored.
+    pub fn set_alive(&self, node: &str, alive: bool) {
+        let mut ring = self.ring.write().unwrap();
+        let Some(weight) = ring.weights.get(node).copied() else {
+            return;
+        };
+        if alive != ring.down.contains(node) {
+            return;
+        }
+
+        if alive {
+            ring.down.remove(node);
+            if let Err(index) = ring.nodes.binary_search_by(|n| n.as_str().cmp(node)) {
+                ring.nodes.insert(index, node.to_string());
+            }
+            for i in 0..self.virtual_nodes(weight) {
+                let hash = self.virtual_node_token(node, i);
+                ring.tokens.insert(hash, node.to_string());
+            }
+        } else {
+            ring.down.insert(node.to_string());
+            ring.nodes.retain(|existing| existing != node);
+            for i in 0..self.virtual_nodes(weight) {
+                let hash = self.virtual_node_token(node, i);
+                ring.tokens.remove(&hash);
+            }
+        }
+        self.changed(ring);
+    }
+
     pub fn remove_node(&self, node: &str) {
         let mut ring = self.ring.write().unwrap();
-        for i in 0..self.replicas {
-            let virtual_node = format!("{}-{}", node, i);
-            let hash = self.hash(&virtual_node);
-            ring.remove(&hash);
+        if ring.weights.contains_key(node) {
+            ring.epoch += 1;
+            self.remove(&mut ring, node);
+            self.changed(ring);
+        }
+    }
+
+    /// Removes a node, with its tokens unless it is down.
+    fn remove(&self, ring: &mut Ring, node: &str) {
+        let Some(weight) = ring.weights.remove(node) else {
+            return;
+        };
+        ring.topology.remove(node);
+        if ring.down.remove(node) {
+            return;
+        }
+        ring.nodes.retain(|existing| existing != node);
+        for i in 0..self.virtual_nodes(weight) {
+            let hash = self.virtual_node_token(node, i);
+            ring.tokens.remove(&hash);
+        }
+    }
+
+    fn virtual_nodes(&self, weight: f64) -> usize {
+        ((self.replicas as f64 * weight).round() as usize).max(1)
+    }
+
+    fn virtual_node_token(&self, node: &str, index: usize) -> u64 {
+        let virtual_node = format!("{}-{}", node, index);
+        self.hash(virtual_node.as_bytes())
+    }
+
+    /// Releases the ring after a change and notifies subscribers.
+    fn changed(&self, mut ring: RwLockWriteGuard<Ring>) {
+        ring.generation += 1;
+        drop(ring);
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+
+    /// Returns a receiver that is notified after every membership change.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Returns a copy of the ring for comparing membership over time.
+    pub fn snapshot(&self) -> Ring {
+        self.ring.read().unwrap().clone()
+    }
+
+    /// This node's membership epoch. A change one node applied after hearing
+    /// of another's has the higher epoch, so a peer reporting a higher epoch
+    /// has seen changes this node may not have. Equal epochs do not imply
+    /// equal views; compare digests for that.
+    pub fn epoch(&self) -> u64 {
+        self.ring.read().unwrap().epoch
+    }
+
+    /// Advances this node's epoch to one a peer reported, if it is higher.
+    pub fn observe_epoch(&self, epoch: u64) {
+        let mut ring = self.ring.write().unwrap();
+        ring.epoch = ring.epoch.max(epoch);
+    }
+
+    /// Hash of the membership: every member, down or not, with its topology.
+    /// Weights and liveness are left out because they are learned over gossip
+    /// and differ between nodes for a while in normal operation.
+    pub fn digest(&self, ring: &Ring) -> u64 {
+        let mut bytes = Vec::new();
+        for node in ring.weights.keys() {
+            bytes.extend_from_slice(node.as_bytes());
+            bytes.push(0);
+            if let Some(topology) = ring.topology.get(node) {
+                for domain in [&topology.zone, &topology.host] {
+                    bytes.extend_from_slice(domain.as_deref().unwrap_or("").as_bytes());
+                    bytes.push(0);
+                }
+            }
+        }
+        self.hash(&bytes)
+    }
+
+    /// Returns the token ranges `(start, end]` whose primary owner is `node`.
+    /// Placements that are not ranged have no fixed ranges and yield none.
+    pub fn primary_ranges(&self, ring: &Ring, node: &str) -> Vec<(u64, u64)> {
+        if !self.placement.ranged() {
+            return Vec::new();
+        }
+        let tokens: Vec<u64> = ring.tokens.keys().copied().collect();
+        let mut ranges = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let start = if i == 0 {
+                tokens[tokens.len() - 1]
+            } else {
+                tokens[i - 1]
+            };
+            if self.owners_in(ring, *token, 1).first().map(String::as_str) == Some(node) {
+                ranges.push((start, *token));
+            }
         }
+        ranges
+    }
+
+    /// Returns the `n` owners of `token` under a given ring snapshot.
+    pub fn owners_in(&self, ring: &Ring, token: u64, n: usize) -> Vec<String> {
+        if ring.topology.is_empty() || n <= 1 {
+            return self.placement.owners(ring, token, n);
+        }
+        let candidates = self.placement.owners(ring, token, ring.nodes.len());
+        ring.spread(candidates, n, self.min_zones)
     }
 
     pub fn get_node(&self, key: &str) -> Option<String> {
-        let hash = self.hash(key);
-        let ring = self.ring.read().unwrap();
-        ring.range(hash..)
-            .next()
-            .or_else(|| ring.iter().next())
-            .map(|(_, node)| node.clone())
+        self.get_n_nodes(key, 1).into_iter().next()
     }
 
     pub fn get_n_nodes(&self, key: &str, n: usize) -> Vec<String> {
-        let hash = self.hash(key);
+        let hash = self.hash(key.as_bytes());
         let ring = self.ring.read().unwrap();
-        let mut nodes = Vec::new();
-        let mut iter = ring.range(hash..).chain(ring.iter());
+        self.owners_in(&ring, hash, n)
+    }
 
-        for (_, node) in iter {
-            if !nodes.contains(node) {
-                nodes.push(node.clone());
-            }
-            if nodes.len() >= n {
-                break;
+    /// Returns the position of `key` on the ring.
+    pub fn key_token(&self, key: &str) -> u64 {
+        self.hash(key.as_bytes())
+    }
+
+    /// Returns whether both `a` and `b` are among the `n` owners of `token`.
+    pub fn shares(&self, token: u64, a: &str, b: &str, n: usize) -> bool {
+        let ring = self.ring.read().unwrap();
+        let owners = self.owners_in(&ring, token, n);
+        owners.iter().any(|node| node == a) && owners.iter().any(|node| node == b)
+    }
+
+    /// Returns the token ranges `(start, end]` that may hold keys replicated
+    /// by both `a` and `b`. A range with `start >= end` wraps around the ring.
+    /// Placements that are not constant between tokens yield the whole ring,
+    /// and callers narrow it down per key with `shares`.
+    pub fn shared_ranges(&self, a: &str, b: &str, n: usize) -> Vec<(u64, u64)> {
+        let ring = self.ring.read().unwrap();
+        if !self.placement.ranged() {
+            return if ring.nodes.iter().any(|node| node == a)
+                && ring.nodes.iter().any(|node| node == b)
+            {
+                vec![(0, 0)]
+            } else {
+                vec![]
+            };
+        }
+
+        let tokens: Vec<u64> = ring.tokens.keys().copied().collect();
+        let mut ranges = Vec::new();
+
+        for (i, token) in tokens.iter().enumerate() {
+            let start = if i == 0 {
+                tokens[tokens.len() - 1]
+            } else {
+                tokens[i - 1]
+            };
+            let owners = self.owners_in(&ring, *token, n);
+            if owners.iter().any(|node| node == a) && owners.iter().any(|node| node == b) {
+                ranges.push((start, *token));
             }
         }
 
-        nodes
+        ranges
+    }
+
+    /// Returns whether `token` falls inside the range `(start, end]`.
+    pub fn in_range(token: u64, (start, end): (u64, u64)) -> bool {
+        if start < end {
+            token > start && token <= end
+        } else {
+            token > start || token <= end
+        }
     }
 
-    fn hash<T: Hash + ?Sized>(&self, key: &T) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        hasher.finish()
+    pub fn hash(&self, bytes: &[u8]) -> u64 {
+        self.algorithm.hash(bytes)
     }
 
     pub fn get_all_nodes(&self) -> Vec<String> {
-        let ring = self.ring.read().unwrap();
-        let mut nodes = ring.values().cloned().collect::<Vec<_>>();
-        nodes.sort();
-        nodes.dedup();
-        nodes
+        self.ring.read().unwrap().nodes.clone()
+    }
+
+    /// Returns every known member, including those that are down.
+    pub fn members(&self) -> Vec<String> {
+        self.ring.read().unwrap().weights.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xxhash64_matches_published_vectors() {
+        assert_eq!(HashAlgorithm::XxHash64.hash(b""), 0xef46db3751d8e999);
+        assert_eq!(HashAlgorithm::XxHash64.hash(b"a"), 0xd24ec4f1a98c6e5b);
+    }
+
+    #[test]
+    fn murmur3_matches_published_vectors() {
+        assert_eq!(HashAlgorithm::Murmur3.hash(b""), 0x0000000000000000);
+        assert_eq!(HashAlgorithm::Murmur3.hash(b"a"), 0x85555565f6597889);
+    }
+
+    #[test]
+    fn digest_and_epoch_follow_membership_only() {
+        let hasher = ConsistentHashing::new(
+            100,
+            HashAlgorithm::XxHash64,
+            Box::new(crate::placement::RingPlacement),
+            0,
+        );
+        hasher.set_weight("a".to_string(), 1.0);
+        hasher.set_weight("b".to_string(), 1.0);
+        let (digest, epoch) = (hasher.digest(&hasher.snapshot()), hasher.epoch());
+
+        hasher.update_weight("b", 2.0);
+        hasher.set_alive("b", false);
+        assert_eq!(hasher.digest(&hasher.snapshot()), digest);
+        assert_eq!(hasher.epoch(), epoch);
+
+        hasher.remove_node("b");
+        assert_ne!(hasher.digest(&hasher.snapshot()), digest);
+        assert_eq!(hasher.epoch(), epoch + 1);
+
+        hasher.observe_epoch(epoch + 5);
+        hasher.set_weight("c".to_string(), 1.0);
+        assert_eq!(hasher.epoch(), epoch + 6);
     }
 }
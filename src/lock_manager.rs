@@ -0,0 +1,315 @@
+// src/lock_manager.rs
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Sender;
+use futures_util::future::join_all;
+use tokio::time::{interval, timeout};
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::cache::Cache;
+use crate::event_listener::{CacheEvent, EventType};
+use crate::hashing::ConsistentHashing;
+use crate::proto::{LockReleasedRequest, LockRequest, LockResponse};
+use crate::replication::Replicator;
+
+/// How long a node is given to take a release announcement.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy)]
+pub enum LockAction {
+    Acquire,
+    Renew,
+    Release,
+}
+
+struct Lease {
+    owner: String,
+    token: u64,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// The lease on `key` expired, was released or belongs to someone else.
+    NotHeld(String),
+    /// The owner of the key could not be reached.
+    Unavailable(String),
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::NotHeld(key) => {
+                write!(f, "Lock on key {} is not held under this lease", key)
+            }
+            LockError::Unavailable(node) => write!(f, "Lock owner {} is unreachable", node),
+        }
+    }
+}
+
+impl From<LockError> for Status {
+    fn from(error: LockError) -> Self {
+        match error {
+            LockError::NotHeld(_) => Status::failed_precondition(error.to_string()),
+            LockError::Unavailable(_) => Status::unavailable(error.to_string()),
+        }
+    }
+}
+
+/// Time-bound locks, granted by the ring owner of their key. Fencing tokens
+/// come from the cache's hybrid logical clock, so they keep increasing when
+/// ownership of a key moves to a node that did not see the earlier leases,
+/// as long as its clock is not behind by more than the time since the last
+/// grant. Leases are not replicated: a new owner may grant a lock that the
+/// previous one still considers held, and the fencing token is what tells the
+/// two holders apart. Releases are announced to every node, so that clients
+/// listening for `LockRelease` events hear of them wherever they listen.
+pub struct LockManager {
+    cache: Arc<Cache>,
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    event_sender: Sender<CacheEvent>,
+    leases: Mutex<HashMap<String, Lease>>,
+    default_lease: Duration,
+    max_lease: Duration,
+}
+
+impl LockManager {
+    pub fn new(
+        cache: Arc<Cache>,
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        event_sender: Sender<CacheEvent>,
+        config: &crate::config::Config,
+    ) -> Self {
+        Self {
+            cache,
+            hasher,
+            replicator,
+            event_sender,
+            leases: Mutex::new(HashMap::new()),
+            default_lease: Duration::from_millis(config.lock_lease_ms),
+            max_lease: Duration::from_millis(config.max_lock_lease_ms),
+        }
+    }
+
+    /// Handles the request if this node owns the key, or forwards it to the
+    /// owner. `client` owns the lease when the request names no client. A
+    /// request marked as forwarded is handled here only if `peer` says another
+    /// node sent it.
+    pub async fn handle(
+        &self,
+        action: LockAction,
+        mut request: LockRequest,
+        client: String,
+        peer: bool,
+    ) -> Result<LockResponse, Status> {
+        if request.key.is_empty() {
+            return Err(Status::invalid_argument("A key is required"));
+        }
+        if request.client_id.is_empty() {
+            request.client_id = client;
+        }
+
+        let local = self.replicator.local_node();
+        let owner = self
+            .hasher
+            .get_node(&request.key)
+            .unwrap_or_else(|| local.to_string());
+        if (peer && request.forwarded) || owner == local {
+            return Ok(match action {
+                LockAction::Acquire => self.acquire(&request),
+                LockAction::Renew => self.renew(&request)?,
+                LockAction::Release => self.release(&request)?,
+            });
+        }
+
+        let mut client = self
+            .replicator
+            .client(&owner)
+            .await
+            .ok_or_else(|| LockError::Unavailable(owner.clone()))?;
+        let request = self.replicator.peer_request(LockRequest {
+            forwarded: true,
+            ..request
+        });
+        let response = match action {
+            LockAction::Acquire => client.acquire_lock(request).await?,
+            LockAction::Renew => client.renew_lease(request).await?,
+            LockAction::Release => client.release_lock(request).await?,
+        };
+        Ok(response.into_inner())
+    }
+
+    /// Grants the lock if it is free. Acquiring a lock the client already
+    /// holds extends the lease and keeps its token.
+    fn acquire(&self, request: &LockRequest) -> LockResponse {
+        let lease = self.lease(request.lease_ms);
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get_mut(&request.key) {
+            Some(current) if current.expires_at > now => {
+                if current.owner != request.client_id {
+                    return LockResponse {
+                        success: false,
+                        token: 0,
+                        lease_ms: (current.expires_at - now).as_millis() as u64,
+                        holder: current.owner.clone(),
+                    };
+                }
+                current.expires_at = now + lease;
+                return Self::granted(current, lease);
+            }
+            Some(_) => {
+                let expired = leases.remove(&request.key).unwrap();
+                self.released(&request.key, &expired, "expired");
+                self.announce(vec![request.key.clone()]);
+            }
+            None => {}
+        }
+
+        let granted = Lease {
+            owner: request.client_id.clone(),
+            token: self.cache.next_version(),
+            expires_at: now + lease,
+        };
+        info!(
+            "Lock on key {} acquired by {} with token {}",
+            request.key, granted.owner, granted.token
+        );
+        let response = Self::granted(&granted, lease);
+        leases.insert(request.key.clone(), granted);
+        response
+    }
+
+    fn renew(&self, request: &LockRequest) -> Result<LockResponse, LockError> {
+        let lease = self.lease(request.lease_ms);
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get_mut(&request.key) {
+            Some(current) if Self::holds(current, request, now) => {
+                current.expires_at = now + lease;
+                Ok(Self::granted(current, lease))
+            }
+            _ => Err(LockError::NotHeld(request.key.clone())),
+        }
+    }
+
+    fn release(&self, request: &LockRequest) -> Result<LockResponse, LockError> {
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get(&request.key) {
+            Some(current) if Self::holds(current, request, now) => {
+                let released = leases.remove(&request.key).unwrap();
+                self.released(&request.key, &released, "released");
+                self.announce(vec![request.key.clone()]);
+                Ok(LockResponse {
+                    success: true,
+                    token: released.token,
+                    lease_ms: 0,
+                    holder: String::new(),
+                })
+            }
+            _ => Err(LockError::NotHeld(request.key.clone())),
+        }
+    }
+
+    /// Releases locks whose lease has expired.
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let mut leases = self.leases.lock().unwrap();
+            let expired: Vec<String> = leases
+                .iter()
+                .filter(|(_, lease)| lease.expires_at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &expired {
+                let lease = leases.remove(key).unwrap();
+                self.released(key, &lease, "expired");
+            }
+            if !expired.is_empty() {
+                self.announce(expired);
+            }
+        }
+    }
+
+    fn holds(lease: &Lease, request: &LockRequest, now: Instant) -> bool {
+        lease.expires_at > now && lease.owner == request.client_id && lease.token == request.token
+    }
+
+    fn lease(&self, lease_ms: u64) -> Duration {
+        if lease_ms == 0 {
+            self.default_lease
+        } else {
+            Duration::from_millis(lease_ms).min(self.max_lease)
+        }
+    }
+
+    fn granted(lease: &Lease, duration: Duration) -> LockResponse {
+        LockResponse {
+            success: true,
+            token: lease.token,
+            lease_ms: duration.as_millis() as u64,
+            holder: lease.owner.clone(),
+        }
+    }
+
+    fn released(&self, key: &str, lease: &Lease, reason: &str) {
+        info!(
+            "Lock on key {} held by {} with token {} {}",
+            key, lease.owner, lease.token, reason
+        );
+        self.announced(key);
+    }
+
+    /// Has every other node emit the `LockRelease` event for `keys`, in the
+    /// background. Nodes are told at the same time over pooled connections,
+    /// and one that does not answer within `ANNOUNCE_TIMEOUT` misses out.
+    fn announce(&self, keys: Vec<String>) {
+        let local = self.replicator.local_node().to_string();
+        let nodes: Vec<String> = self
+            .hasher
+            .get_all_nodes()
+            .into_iter()
+            .filter(|node| *node != local)
+            .collect();
+        let replicator = self.replicator.clone();
+        tokio::spawn(async move {
+            join_all(nodes.iter().map(|node| {
+                let replicator = &replicator;
+                let keys = keys.clone();
+                async move {
+                    let request = replicator.peer_request(LockReleasedRequest { keys });
+                    let announced = async {
+                        let mut client = replicator.client(node).await?;
+                        client.lock_released(request).await.ok()
+                    };
+                    if timeout(ANNOUNCE_TIMEOUT, announced)
+                        .await
+                        .ok()
+                        .flatten()
+                        .is_none()
+                    {
+                        warn!("Failed to announce lock releases to node {}", node);
+                    }
+                }
+            }))
+            .await;
+        });
+    }
+
+    /// Emits the `LockRelease` event here for a lock released on its owner.
+    pub fn announced(&self, key: &str) {
+        let _ = self.event_sender.send(CacheEvent {
+            event_type: EventType::LockRelease,
+            key: key.to_string(),
+        });
+    }
+}
@@ -1,39 +1,60 @@
 // src/main.rs
 
+mod anti_entropy;
 mod cache;
+mod cluster_view;
 mod config;
+mod counter;
+mod discovery;
 mod event_listener;
 mod fallback;
+mod gossip;
 mod hashing;
+mod lock_manager;
 mod monitoring;
+mod node_identity;
+mod placement;
 mod pod_discovery;
+mod rebalancer;
 mod replication;
 mod search_index;
 mod security;
+mod structures;
 mod transaction_manager;
+mod two_phase_commit;
 
 mod proto {
     tonic::include_proto!("cache");
 }
 
-use crate::cache::Cache;
+use crate::anti_entropy::{AntiEntropy, MerkleTree, SyncScope};
+use crate::cache::{Cache, Expected};
+use crate::cluster_view::ClusterViews;
 use crate::config::Config;
+use crate::counter::CounterError;
+use crate::discovery::Membership;
 use crate::event_listener::{EventListener, EventType as ListenerEventType};
 use crate::fallback::{Fallback, RedisFallback};
+use crate::gossip::Gossip;
 use crate::hashing::ConsistentHashing;
+use crate::lock_manager::{LockAction, LockManager};
 use crate::monitoring::Monitoring;
-use crate::pod_discovery::PodDiscovery;
+use crate::node_identity::NodeIdentity;
 use crate::proto::cache_service_client::CacheServiceClient;
 use crate::proto::cache_service_server::{CacheService, CacheServiceServer};
 use crate::proto::*;
-use crate::replication::Replicator;
+use crate::rebalancer::Rebalancer;
+use crate::replication::{Mutation, Replicator};
 use crate::search_index::SearchIndex;
 use crate::security::Security;
+use crate::transaction_manager::{IsolationLevel, Operation, TransactionMode};
+use crate::two_phase_commit::TwoPhaseCommit;
 use bytes::Bytes;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::sync::{mpsc, Notify};
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
 use tracing::{info, warn};
 
 #[tokio::main]
@@ -41,11 +62,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
     let config = Config::load();
+    let identity = NodeIdentity::from_config(&config);
+    info!("Node {} has address {}", identity.id, identity.address);
+    if config.transaction_mode == TransactionMode::Applied {
+        warn!("Transactions apply writes before commit; other clients can read uncommitted data");
+    }
 
     let event_listener = Arc::new(EventListener::new());
     let event_sender = event_listener.get_sender();
 
-    let cache = Arc::new(Cache::new(config.clone(), event_sender.clone()));
+    let monitoring = Arc::new(Monitoring::new());
+
+    let (replication_sender, replication_receiver) = mpsc::unbounded_channel();
+    let cache = Arc::new(Cache::new(
+        config.clone(),
+        event_sender.clone(),
+        replication_sender,
+        monitoring.clone(),
+        identity.address.clone(),
+    ));
 
     // Start event listener
     {
@@ -58,32 +93,135 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         });
     }
 
-    let hasher = Arc::new(ConsistentHashing::new(100));
-    hasher.add_node("localhost".to_string());
+    let placement = config
+        .placement
+        .build(config.hash_algorithm, config.bounded_load_factor);
+    info!("Using {} placement", placement.name());
+    let hasher = Arc::new(ConsistentHashing::new(
+        100,
+        config.hash_algorithm,
+        placement,
+        config.min_zones,
+    ));
+    hasher.set_topology(identity.address.clone(), identity.topology.clone());
+    hasher.set_weight(identity.address.clone(), identity.weight);
+    let security = Security::new(&config);
     let replicator = Arc::new(Replicator::new(
         hasher.clone(),
         config.replication_factor,
-        "localhost".to_string(),
+        identity.address.clone(),
+        monitoring.clone(),
+        security.client_tls_config.clone(),
+        config.peer_token.clone(),
+    ));
+    tokio::spawn(replicator.clone().run(replication_receiver));
+
+    let gossip = Arc::new(Gossip::new(
+        hasher.clone(),
+        replicator.clone(),
+        monitoring.clone(),
+        identity.address.clone(),
+        &config,
+    ));
+    tokio::spawn(gossip.clone().run());
+
+    let resync = Arc::new(Notify::new());
+    let cluster_views = Arc::new(ClusterViews::new(
+        hasher.clone(),
+        replicator.clone(),
+        resync.clone(),
+        &config,
     ));
+    tokio::spawn(cluster_views.clone().run());
+
+    let anti_entropy = AntiEntropy::new(
+        cache.clone(),
+        hasher.clone(),
+        replicator.clone(),
+        monitoring.clone(),
+        identity.address.clone(),
+        &config,
+    );
+    tokio::spawn(anti_entropy.run());
+
+    let rebalancer = Rebalancer::new(
+        cache.clone(),
+        hasher.clone(),
+        replicator.clone(),
+        identity.address.clone(),
+    );
+    tokio::spawn(rebalancer.run());
+
+    // Purge expired tombstones
+    {
+        let cache_clone = cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                cache_clone.purge_tombstones();
+            }
+        });
+    }
+
+    // Discard transactions that outlived the transaction timeout
+    if config.enable_transactions {
+        let cache_clone = cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let expired = cache_clone.cleanup_expired_transactions();
+                if expired > 0 {
+                    warn!("Discarded {} expired transactions", expired);
+                }
+            }
+        });
+    }
+
+    let two_phase = Arc::new(
+        TwoPhaseCommit::new(cache.clone(), hasher.clone(), replicator.clone(), &config)
+            .expect("Failed to open the transaction log"),
+    );
+    tokio::spawn(two_phase.clone().run());
+
+    let locks = Arc::new(LockManager::new(
+        cache.clone(),
+        hasher.clone(),
+        replicator.clone(),
+        event_sender.clone(),
+        &config,
+    ));
+    tokio::spawn(locks.clone().run());
+
     let fallback = Arc::new(RedisFallback::new(&config.redis_url).await);
     let search_index = Arc::new(SearchIndex::new());
-    let security = Security::new(&config);
 
     if config.enable_monitoring {
-        let monitoring = Monitoring::new();
-        monitoring.serve(cache.clone(), hasher.clone(), Arc::new(config.clone()));
+        monitoring.serve(
+            cache.clone(),
+            cluster_views.clone(),
+            search_index.clone(),
+            Arc::new(config.clone()),
+        );
     }
 
-    let pod_discovery = PodDiscovery::new(hasher.clone());
-    tokio::spawn(async move { pod_discovery.start().await });
+    let discovery = config.discovery.build(&config);
+    info!("Using {} discovery", discovery.name());
+    tokio::spawn(discovery.run(Membership::new(hasher.clone(), identity, resync)));
 
     let addr = config.local_address.parse()?;
     let cache_service = MyCacheService {
         cache: cache.clone(),
         replicator,
+        gossip,
+        cluster_views,
+        two_phase,
+        locks,
         hasher: hasher.clone(),
         fallback,
         search_index,
+        monitoring,
         security,
         config: config.clone(),
         event_listener: event_listener.clone(),
@@ -105,93 +243,269 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Applies a replicated mutation and indexes the new value. Returns whether the
+/// mutation was newer than the local state, or an error for a corrupt value.
+#[allow(clippy::result_large_err)]
+fn apply_replicated(
+    cache: &Cache,
+    search_index: &SearchIndex,
+    request: ReplicationRequest,
+) -> Result<bool, Status> {
+    let key = request.key.clone();
+    let mutation = Mutation::from_request(request)
+        .ok_or_else(|| Status::data_loss(format!("Replicated value for key {} is corrupt", key)))?;
+    let indexed = match &mutation {
+        Mutation::Put { key, value, .. } => cache::decode(value)
+            .and_then(|value| String::from_utf8(value.to_vec()).ok())
+            .map(|value| (key.clone(), value)),
+        Mutation::Delete { .. }
+        | Mutation::Counter { .. }
+        | Mutation::Structure { .. }
+        | Mutation::StructureDelta { .. } => None,
+    };
+    let applied = cache.apply_remote(mutation);
+
+    if applied {
+        if let Some((key, value)) = indexed {
+            search_index.add_document(&key, &value);
+        }
+    }
+    Ok(applied)
+}
+
+/// Converts a TTL in seconds from a request; 0 or less means none.
+fn request_ttl(ttl: i64) -> Option<Duration> {
+    if ttl > 0 {
+        Some(Duration::from_secs(ttl as u64))
+    } else {
+        None
+    }
+}
+
+/// Converts a remaining TTL for a response, rounding up so that a value about
+/// to expire is not reported as never expiring.
+fn response_ttl(ttl: Option<Duration>) -> i64 {
+    ttl.map_or(0, |ttl| ttl.as_secs_f64().ceil().max(1.0) as i64)
+}
+
 struct MyCacheService {
     cache: Arc<Cache>,
     replicator: Arc<Replicator>,
+    gossip: Arc<Gossip>,
+    cluster_views: Arc<ClusterViews>,
+    two_phase: Arc<TwoPhaseCommit>,
+    locks: Arc<LockManager>,
     hasher: Arc<ConsistentHashing>,
     fallback: Arc<dyn Fallback + Send + Sync>,
     search_index: Arc<SearchIndex>,
+    monitoring: Arc<Monitoring>,
     security: Security,
     config: Config,
     event_listener: Arc<EventListener>,
 }
 
-#[tonic::async_trait]
-impl CacheService for MyCacheService {
-    type ListenEventsStream = tokio_stream::wrappers::ReceiverStream<Result<EventResponse, Status>>;
-
-    async fn get(&self, request: Request<CacheKey>) -> Result<Response<CacheValue>, Status> {
-        self.security.authenticate(&request)?;
-
-        let key = request.into_inner().key;
+impl MyCacheService {
+    /// Connects to the owner of `key` if that is another node. Requests that
+    /// read and modify a key in one step go there, so that concurrent ones
+    /// are applied one after the other. Returns `None` when this node
+    /// handles the request: it owns the key, or another node forwarded the
+    /// request here, which `forwarded` only says once `Security::is_peer`
+    /// vouched for the sender.
+    #[allow(clippy::result_large_err)]
+    async fn owner_of(
+        &self,
+        key: &str,
+        forwarded: bool,
+    ) -> Result<Option<CacheServiceClient<Channel>>, Status> {
+        let local = self.replicator.local_node();
+        let owner = self
+            .hasher
+            .get_node(key)
+            .unwrap_or_else(|| local.to_string());
+        if forwarded || owner == local {
+            return Ok(None);
+        }
+        match self.replicator.connect(&owner).await {
+            Some(client) => Ok(Some(client)),
+            None => Err(Status::unavailable(format!(
+                "Owner {} of key {} is unreachable",
+                owner, key
+            ))),
+        }
+    }
 
-        if let Some(value) = self.cache.get(&key) {
+    /// Reads `key` from this node, then from replicas, then from the fallback.
+    #[allow(clippy::result_large_err)]
+    async fn read(&self, key: String) -> Result<CacheValue, Status> {
+        if let Some((value, version)) = self.cache.get_versioned(&key) {
             info!("Cache hit for key: {}", key);
-            return Ok(Response::new(CacheValue {
+            self.monitoring.cache_hits.with_label_values(&["get"]).inc();
+            return Ok(CacheValue {
                 value: value.to_vec(),
                 found: true,
-            }));
+                version,
+                ttl: response_ttl(self.cache.remaining_ttl(&key)),
+            });
         }
-
-        let nodes = self
-            .hasher
-            .get_n_nodes(&key, self.replicator.replication_factor + 1);
-
-        for node in nodes {
-            if node == "localhost" {
-                continue;
+        self.monitoring
+            .cache_misses
+            .with_label_values(&["get"])
+            .inc();
+
+        if self.config.read_repair {
+            let local = self.cache.replica_state(&key);
+            if let Some(newest) = self.replicator.read_repair(&key, local).await {
+                let found = match &newest {
+                    Mutation::Put { value, version, .. } => Some((
+                        cache::decode(value)
+                            .ok_or_else(|| Status::data_loss("Corrupt replica value"))?,
+                        version,
+                    )),
+                    Mutation::Counter {
+                        counter, version, ..
+                    } => Some((Bytes::from(counter.value().to_string()), version)),
+                    Mutation::Structure {
+                        structure, version, ..
+                    } => Some((structure.render(), version)),
+                    Mutation::Delete { .. } | Mutation::StructureDelta { .. } => None,
+                };
+                if let Some((value, version)) = found {
+                    info!("Cache hit from replicas for key: {}", key);
+                    let response = CacheValue {
+                        value: value.to_vec(),
+                        found: true,
+                        version: *version,
+                        ttl: response_ttl(newest.ttl()),
+                    };
+                    self.cache.apply_remote(newest);
+                    return Ok(response);
+                }
             }
-            let addr = format!("http://{}:50051", node);
-            let mut client = match CacheServiceClient::connect(addr.clone()).await {
-                Ok(client) => client,
-                Err(e) => {
-                    warn!("Failed to connect to node {}: {}", node, e);
+        } else {
+            // Same-zone replicas are tried first to keep reads local
+            let nodes = self.hasher.by_proximity(
+                self.hasher
+                    .get_n_nodes(&key, self.replicator.replication_factor + 1),
+                self.hasher.zone_of(self.replicator.local_node()).as_deref(),
+            );
+
+            for node in nodes {
+                if node == self.replicator.local_node() {
                     continue;
                 }
-            };
-            let request = tonic::Request::new(CacheKey { key: key.clone() });
-            match client.get(request).await {
-                Ok(response) => {
-                    let cache_value = response.into_inner();
-                    if cache_value.found {
-                        info!("Cache hit from node {} for key: {}", node, key);
-                        self.cache
-                            .put(key.clone(), Bytes::from(cache_value.value.clone()), None);
-                        return Ok(Response::new(CacheValue {
-                            value: cache_value.value,
-                            found: true,
-                        }));
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to get key {} from node {}: {}", key, node, e);
+                let Some(mut client) = self.replicator.connect(&node).await else {
                     continue;
+                };
+                let request = tonic::Request::new(CacheKey {
+                    key: key.clone(),
+                    ..Default::default()
+                });
+                match client.get(request).await {
+                    Ok(response) => {
+                        let cache_value = response.into_inner();
+                        if cache_value.found {
+                            info!("Cache hit from node {} for key: {}", node, key);
+                            self.cache.apply_remote(Mutation::put(
+                                key.clone(),
+                                &cache_value.value,
+                                request_ttl(cache_value.ttl),
+                                cache_value.version,
+                            ));
+                            return Ok(cache_value);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to get key {} from node {}: {}", key, node, e);
+                        continue;
+                    }
                 }
             }
         }
 
         if let Some(value) = self.fallback.get(&key).await {
             info!("Cache miss for key: {}. Fetched from fallback.", key);
-            self.cache.put(key.clone(), value.clone(), None);
-            let _ = self
-                .replicator
-                .replicate(
-                    key.clone(),
-                    value.clone(),
-                    self.cache.default_ttl.map_or(0, |d| d.as_secs() as i64),
-                )
-                .await;
-            return Ok(Response::new(CacheValue {
+            let version = self
+                .cache
+                .put(key.clone(), value.clone(), self.cache.default_ttl)?;
+            Ok(CacheValue {
                 value: value.to_vec(),
                 found: true,
-            }));
+                version,
+                ttl: response_ttl(self.cache.default_ttl),
+            })
         } else {
             info!("Cache miss for key: {}. No data found in fallback.", key);
-            return Ok(Response::new(CacheValue {
+            Ok(CacheValue {
                 value: vec![],
                 found: false,
-            }));
+                version: 0,
+                ttl: 0,
+            })
+        }
+    }
+
+    /// Shared by `Increment` and `Decrement`, which negates the delta.
+    #[allow(clippy::result_large_err)]
+    fn update_counter(
+        &self,
+        request: CounterRequest,
+        negate: bool,
+    ) -> Result<Response<CounterResponse>, Status> {
+        let mut delta = request.delta.unwrap_or(1);
+        if negate {
+            delta = delta.checked_neg().ok_or(CounterError::Overflow)?;
         }
+        let (value, version) = self.cache.increment(
+            &request.key,
+            delta,
+            request.initial.unwrap_or(0),
+            request_ttl(request.ttl),
+        )?;
+        info!("Counter {} is now {}", request.key, value);
+        Ok(Response::new(CounterResponse { value, version }))
+    }
+}
+
+#[tonic::async_trait]
+impl CacheService for MyCacheService {
+    type ListenEventsStream = tokio_stream::wrappers::ReceiverStream<Result<EventResponse, Status>>;
+    type ReplicateStreamStream =
+        tokio_stream::wrappers::ReceiverStream<Result<ReplicationAck, Status>>;
+    type GetKeyVersionsStream = tokio_stream::wrappers::ReceiverStream<Result<KeyVersion, Status>>;
+
+    async fn get(&self, request: Request<CacheKey>) -> Result<Response<CacheValue>, Status> {
+        self.security.authenticate(&request)?;
+
+        let CacheKey {
+            key,
+            transaction_id,
+        } = request.into_inner();
+
+        if !transaction_id.is_empty() {
+            if let Some(known) = self.cache.get_in_transaction(&transaction_id, &key)? {
+                return Ok(Response::new(match known {
+                    Some((value, version)) => CacheValue {
+                        value: value.to_vec(),
+                        found: true,
+                        version,
+                        ttl: response_ttl(self.cache.remaining_ttl(&key)),
+                    },
+                    None => CacheValue {
+                        found: false,
+                        ..Default::default()
+                    },
+                }));
+            }
+        }
+
+        let value = self.read(key.clone()).await?;
+        if !transaction_id.is_empty() {
+            let found = value
+                .found
+                .then(|| (Bytes::from(value.value.clone()), value.version));
+            self.cache.record_read(&transaction_id, &key, found)?;
+        }
+        Ok(Response::new(value))
     }
 
     async fn put(&self, request: Request<CacheEntry>) -> Result<Response<PutResponse>, Status> {
@@ -205,18 +519,32 @@ impl CacheService for MyCacheService {
         };
         let value = Bytes::from(entry.value.clone());
 
-        self.cache.put(entry.key.clone(), value.clone(), ttl);
+        if !entry.transaction_id.is_empty() {
+            self.cache.write_in_transaction(
+                &entry.transaction_id,
+                Operation::Put {
+                    key: entry.key.clone(),
+                    value,
+                    ttl,
+                },
+            )?;
+            info!(
+                "Wrote key: {} in transaction {}",
+                entry.key, entry.transaction_id
+            );
+            return Ok(Response::new(PutResponse { success: true }));
+        }
+
+        self.cache.put(entry.key.clone(), value, ttl)?;
 
         if let Ok(value_str) = String::from_utf8(entry.value.clone()) {
             self.search_index.add_document(&entry.key, &value_str);
         }
 
-        let _ = self
-            .replicator
-            .replicate(entry.key.clone(), value.clone(), entry.ttl)
-            .await;
-
-        info!("Stored key: {} in cache and replicated.", entry.key);
+        info!(
+            "Stored key: {} in cache and queued it for replication.",
+            entry.key
+        );
 
         Ok(Response::new(PutResponse { success: true }))
     }
@@ -225,16 +553,30 @@ impl CacheService for MyCacheService {
         &self,
         request: Request<TransactionRequest>,
     ) -> Result<Response<TransactionResponse>, Status> {
-        self.security.authenticate(&request)?;
+        let client = self.security.authenticate(&request)?;
 
         if !self.config.enable_transactions {
             warn!("Attempted to begin transaction, but transactions are disabled");
             return Err(Status::unimplemented("Transactions are disabled"));
         }
 
-        let transaction_id = self.cache.transaction_manager.begin_transaction();
+        let isolation = request.into_inner().isolation;
+        let isolation = if isolation.is_empty() {
+            self.config.transaction_isolation
+        } else {
+            isolation
+                .parse::<IsolationLevel>()
+                .map_err(Status::invalid_argument)?
+        };
+        let transaction_id = self
+            .cache
+            .begin_transaction(&client, isolation)
+            .inspect_err(|e| warn!("Failed to begin transaction: {}", e))?;
 
-        info!("Started transaction with ID: {}", transaction_id);
+        info!(
+            "Started transaction with ID: {} ({:?}) for client {}",
+            transaction_id, isolation, client
+        );
 
         Ok(Response::new(TransactionResponse {
             success: true,
@@ -250,9 +592,24 @@ impl CacheService for MyCacheService {
 
         let transaction_id = request.into_inner().transaction_id;
 
-        self.cache.commit_transaction(&transaction_id);
+        let operations = self
+            .two_phase
+            .commit(&transaction_id)
+            .await
+            .inspect_err(|e| warn!("Commit of transaction {} failed: {}", transaction_id, e))?;
+        for operation in &operations {
+            if let Operation::Put { key, value, .. } = operation {
+                if let Ok(value_str) = std::str::from_utf8(value) {
+                    self.search_index.add_document(key, value_str);
+                }
+            }
+        }
 
-        info!("Committed transaction ID: {}", transaction_id);
+        info!(
+            "Committed transaction ID: {} ({} writes)",
+            transaction_id,
+            operations.len()
+        );
 
         Ok(Response::new(TransactionResponse {
             success: true,
@@ -268,9 +625,12 @@ impl CacheService for MyCacheService {
 
         let transaction_id = request.into_inner().transaction_id;
 
-        self.cache.rollback_transaction(&transaction_id);
+        let discarded = self.cache.rollback_transaction(&transaction_id)?;
 
-        info!("Rolled back transaction ID: {}", transaction_id);
+        info!(
+            "Rolled back transaction ID: {} ({} writes discarded)",
+            transaction_id, discarded
+        );
 
         Ok(Response::new(TransactionResponse {
             success: true,
@@ -297,12 +657,14 @@ impl CacheService for MyCacheService {
                     ListenerEventType::Put => EventType::Put as i32,
                     ListenerEventType::Evict => EventType::Evict as i32,
                     ListenerEventType::Expire => EventType::Expire as i32,
+                    ListenerEventType::LockRelease => EventType::LockRelease as i32,
                 };
 
                 let cache_entry = Some(CacheEntry {
                     key: event.key.clone(),
                     value: vec![],
                     ttl: 0,
+                    ..Default::default()
                 });
 
                 let event_response = EventResponse {
@@ -331,20 +693,32 @@ impl CacheService for MyCacheService {
         let mut values = std::collections::HashMap::new();
 
         for key in keys {
-            if let Some(value) = self.cache.get(&key) {
+            if let Some((value, version)) = self.cache.get_versioned(&key) {
+                self.monitoring
+                    .cache_hits
+                    .with_label_values(&["batch_get"])
+                    .inc();
                 values.insert(
                     key.clone(),
                     CacheValue {
                         value: value.to_vec(),
                         found: true,
+                        version,
+                        ttl: response_ttl(self.cache.remaining_ttl(&key)),
                     },
                 );
             } else {
+                self.monitoring
+                    .cache_misses
+                    .with_label_values(&["batch_get"])
+                    .inc();
                 values.insert(
                     key.clone(),
                     CacheValue {
                         value: vec![],
                         found: false,
+                        version: 0,
+                        ttl: 0,
                     },
                 );
             }
@@ -368,18 +742,28 @@ impl CacheService for MyCacheService {
             };
             let value = Bytes::from(entry.value.clone());
 
-            self.cache.put(entry.key.clone(), value.clone(), ttl);
+            if !entry.transaction_id.is_empty() {
+                self.cache.write_in_transaction(
+                    &entry.transaction_id,
+                    Operation::Put {
+                        key: entry.key.clone(),
+                        value,
+                        ttl,
+                    },
+                )?;
+                continue;
+            }
+
+            self.cache.put(entry.key.clone(), value, ttl)?;
 
             if let Ok(value_str) = String::from_utf8(entry.value.clone()) {
                 self.search_index.add_document(&entry.key, &value_str);
             }
 
-            let _ = self
-                .replicator
-                .replicate(entry.key.clone(), value.clone(), entry.ttl)
-                .await;
-
-            info!("Stored key: {} in cache and replicated.", entry.key);
+            info!(
+                "Stored key: {} in cache and queued it for replication.",
+                entry.key
+            );
         }
 
         Ok(Response::new(BatchPutResponse { success: true }))
@@ -388,9 +772,19 @@ impl CacheService for MyCacheService {
     async fn evict(&self, request: Request<CacheKey>) -> Result<Response<EvictResponse>, Status> {
         self.security.authenticate(&request)?;
 
-        let key = request.into_inner().key;
+        let CacheKey {
+            key,
+            transaction_id,
+        } = request.into_inner();
 
-        self.cache.evict(&key);
+        if !transaction_id.is_empty() {
+            self.cache
+                .write_in_transaction(&transaction_id, Operation::Evict { key: key.clone() })?;
+            info!("Evicted key: {} in transaction {}", key, transaction_id);
+            return Ok(Response::new(EvictResponse { success: true }));
+        }
+
+        self.cache.evict(&key)?;
 
         info!("Evicted key: {} from cache.", key);
 
@@ -403,26 +797,743 @@ impl CacheService for MyCacheService {
         let key = request.into_inner().key;
 
         if let Some(value) = self.fallback.get(&key).await {
-            self.cache.put(key.clone(), value.clone(), None);
-            let _ = self
-                .replicator
-                .replicate(
-                    key.clone(),
-                    value.clone(),
-                    self.cache.default_ttl.map_or(0, |d| d.as_secs() as i64),
-                )
-                .await;
+            let version = self
+                .cache
+                .put(key.clone(), value.clone(), self.cache.default_ttl)?;
             info!("Refreshed key: {} from fallback.", key);
-            return Ok(Response::new(CacheValue {
+            Ok(Response::new(CacheValue {
                 value: value.to_vec(),
                 found: true,
-            }));
+                version,
+                ttl: response_ttl(self.cache.default_ttl),
+            }))
         } else {
             info!("Failed to refresh key: {}. No data found in fallback.", key);
-            return Ok(Response::new(CacheValue {
+            Ok(Response::new(CacheValue {
                 value: vec![],
                 found: false,
-            }));
+                version: 0,
+                ttl: 0,
+            }))
+        }
+    }
+
+    async fn replicate(
+        &self,
+        request: Request<ReplicationRequest>,
+    ) -> Result<Response<ReplicationResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let applied = apply_replicated(&self.cache, &self.search_index, request.into_inner())?;
+
+        Ok(Response::new(ReplicationResponse { applied }))
+    }
+
+    async fn replicate_stream(
+        &self,
+        request: Request<tonic::Streaming<ReplicationBatch>>,
+    ) -> Result<Response<Self::ReplicateStreamStream>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let mut batches = request.into_inner();
+        let cache = self.cache.clone();
+        let search_index = self.search_index.clone();
+        let (tx, rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            loop {
+                match batches.message().await {
+                    Ok(Some(batch)) => {
+                        let applied = batch
+                            .entries
+                            .into_iter()
+                            .map(|entry| {
+                                apply_replicated(&cache, &search_index, entry).unwrap_or_else(|e| {
+                                    warn!("Skipped replicated mutation: {}", e.message());
+                                    false
+                                })
+                            })
+                            .filter(|applied| *applied)
+                            .count();
+                        let ack = ReplicationAck {
+                            sequence: batch.sequence,
+                            applied: applied as u32,
+                        };
+                        if tx.send(Ok(ack)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Replication stream failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(
+            rx,
+        )))
+    }
+
+    async fn read_replica(
+        &self,
+        request: Request<CacheKey>,
+    ) -> Result<Response<ReplicationRequest>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let key = request.into_inner().key;
+        let state = match self.cache.replica_state(&key) {
+            Some(mutation) => mutation.to_request(),
+            None => ReplicationRequest {
+                key,
+                ..Default::default()
+            },
+        };
+
+        Ok(Response::new(state))
+    }
+
+    async fn get_merkle_tree(
+        &self,
+        request: Request<MerkleTreeRequest>,
+    ) -> Result<Response<MerkleTreeResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let request = request.into_inner();
+        if request.depth > anti_entropy::MERKLE_DEPTH {
+            return Err(Status::invalid_argument("Merkle tree depth is too large"));
+        }
+        let ranges = anti_entropy::from_token_ranges(&request.ranges);
+        let scope = SyncScope {
+            ranges: &ranges,
+            local: self.replicator.local_node(),
+            peer: &request.node,
+            replicas: self.replicator.replication_factor + 1,
+        };
+        let tree = anti_entropy::build_tree(&self.cache, &self.hasher, &scope, request.depth);
+
+        Ok(Response::new(MerkleTreeResponse {
+            hashes: tree.into_hashes(),
+        }))
+    }
+
+    async fn get_key_versions(
+        &self,
+        request: Request<KeyVersionsRequest>,
+    ) -> Result<Response<Self::GetKeyVersionsStream>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let request = request.into_inner();
+        if request.depth > anti_entropy::MERKLE_DEPTH {
+            return Err(Status::invalid_argument("Merkle tree depth is too large"));
+        }
+        let ranges = anti_entropy::from_token_ranges(&request.ranges);
+        let scope = SyncScope {
+            ranges: &ranges,
+            local: self.replicator.local_node(),
+            peer: &request.node,
+            replicas: self.replicator.replication_factor + 1,
+        };
+        let leaves: std::collections::HashSet<usize> =
+            request.leaves.iter().map(|leaf| *leaf as usize).collect();
+        let versions: Vec<KeyVersion> =
+            anti_entropy::collect_versions(&self.cache, &self.hasher, &scope)
+                .into_iter()
+                .filter(|(token, _, _)| {
+                    leaves.contains(&MerkleTree::leaf_of(*token, request.depth))
+                })
+                .map(|(_, key, version)| KeyVersion { key, version })
+                .collect();
+
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(async move {
+            for key_version in versions {
+                if tx.send(Ok(key_version)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(
+            rx,
+        )))
+    }
+
+    async fn handshake(&self, request: Request<NodeInfo>) -> Result<Response<NodeInfo>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let local = self.replicator.node_info();
+        let peer = request.into_inner();
+        if peer != local {
+            warn!(
+                "Rejecting peer using hash algorithm {} (fingerprint {:#x}), local is {}",
+                peer.hash_algorithm, peer.hash_fingerprint, local.hash_algorithm
+            );
+            return Err(Status::failed_precondition(format!(
+                "Hash algorithm mismatch: local node uses {}",
+                local.hash_algorithm
+            )));
+        }
+
+        Ok(Response::new(local))
+    }
+
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        Ok(Response::new(self.gossip.handle_ping(request.into_inner())))
+    }
+
+    async fn ping_req(
+        &self,
+        request: Request<PingReqRequest>,
+    ) -> Result<Response<PingResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let response = self.gossip.handle_ping_req(request.into_inner()).await;
+        Ok(Response::new(response))
+    }
+
+    async fn get_cluster_view(
+        &self,
+        request: Request<ClusterViewRequest>,
+    ) -> Result<Response<ClusterView>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let request = request.into_inner();
+        if !request.node.is_empty() {
+            self.cluster_views
+                .observe(&request.node, request.epoch, request.digest);
+        }
+        Ok(Response::new(
+            self.cluster_views.local_view(request.summary),
+        ))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        let WatchRequest {
+            transaction_id,
+            keys,
+        } = request.into_inner();
+        self.cache.watch(&transaction_id, &keys)?;
+
+        info!(
+            "Watching {} keys in transaction {}",
+            keys.len(),
+            transaction_id
+        );
+
+        Ok(Response::new(TransactionResponse {
+            success: true,
+            message: "Keys watched".to_string(),
+        }))
+    }
+
+    async fn compare_and_set(
+        &self,
+        request: Request<CompareAndSetRequest>,
+    ) -> Result<Response<CompareAndSetResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .compare_and_set(self.security.peer_request(CompareAndSetRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+
+        let expected = match request.expected {
+            Some(compare_and_set_request::Expected::ExpectedVersion(version)) => {
+                Expected::Version(version)
+            }
+            Some(compare_and_set_request::Expected::ExpectedValue(value)) => {
+                Expected::Value(Bytes::from(value))
+            }
+            None => {
+                return Err(Status::invalid_argument(
+                    "An expected version or value is required",
+                ))
+            }
+        };
+        let ttl = if request.ttl > 0 {
+            Some(Duration::from_secs(request.ttl as u64))
+        } else {
+            None
+        };
+
+        match self.cache.compare_and_set(
+            request.key.clone(),
+            expected,
+            Bytes::from(request.value.clone()),
+            ttl,
+        )? {
+            Ok(version) => {
+                if let Ok(value_str) = std::str::from_utf8(&request.value) {
+                    self.search_index.add_document(&request.key, value_str);
+                }
+                info!("Compare-and-set of key {} succeeded", request.key);
+                Ok(Response::new(CompareAndSetResponse {
+                    success: true,
+                    version,
+                }))
+            }
+            Err(version) => {
+                info!(
+                    "Compare-and-set of key {} failed at version {}",
+                    request.key, version
+                );
+                Ok(Response::new(CompareAndSetResponse {
+                    success: false,
+                    version,
+                }))
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        request: Request<PrepareRequest>,
+    ) -> Result<Response<PrepareResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        Ok(Response::new(self.two_phase.prepare(request.into_inner())))
+    }
+
+    async fn decide(
+        &self,
+        request: Request<DecideRequest>,
+    ) -> Result<Response<DecideResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        let request = request.into_inner();
+        self.two_phase
+            .decide(&request.transaction_id, request.commit);
+        Ok(Response::new(DecideResponse { success: true }))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionInfo>, Status> {
+        self.security.authenticate(&request)?;
+
+        let transaction_id = request.into_inner().transaction_id;
+        Ok(Response::new(
+            self.cache.transaction_manager.describe(&transaction_id)?,
+        ))
+    }
+
+    async fn list_transactions(
+        &self,
+        request: Request<ListTransactionsRequest>,
+    ) -> Result<Response<ListTransactionsResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        Ok(Response::new(ListTransactionsResponse {
+            transactions: self.cache.transaction_manager.list(),
+        }))
+    }
+
+    async fn abort_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let client = self.security.authenticate(&request)?;
+        self.security.authorize_admin(&request)?;
+
+        let transaction_id = request.into_inner().transaction_id;
+        let discarded = self.cache.abort_transaction(&transaction_id)?;
+
+        warn!(
+            "Transaction ID: {} aborted by admin {} ({} writes discarded)",
+            transaction_id, client, discarded
+        );
+
+        Ok(Response::new(TransactionResponse {
+            success: true,
+            message: "Transaction aborted".to_string(),
+        }))
+    }
+
+    async fn get_decision(
+        &self,
+        request: Request<DecisionQuery>,
+    ) -> Result<Response<DecisionResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        Ok(Response::new(
+            self.two_phase
+                .decision(&request.into_inner().transaction_id),
+        ))
+    }
+
+    async fn increment(
+        &self,
+        request: Request<CounterRequest>,
+    ) -> Result<Response<CounterResponse>, Status> {
+        self.security.authenticate(&request)?;
+        self.update_counter(request.into_inner(), false)
+    }
+
+    async fn decrement(
+        &self,
+        request: Request<CounterRequest>,
+    ) -> Result<Response<CounterResponse>, Status> {
+        self.security.authenticate(&request)?;
+        self.update_counter(request.into_inner(), true)
+    }
+
+    async fn hash_set(
+        &self,
+        request: Request<HashSetRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .hash_set(self.security.peer_request(HashSetRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let fields = request
+            .fields
+            .into_iter()
+            .map(|(field, value)| (field, Bytes::from(value)))
+            .collect();
+        let (added, version) =
+            self.cache
+                .hash_set(&request.key, fields, request_ttl(request.ttl))?;
+        Ok(Response::new(StructureResponse {
+            count: added as u64,
+            version,
+        }))
+    }
+
+    async fn hash_get(
+        &self,
+        request: Request<HashFieldsRequest>,
+    ) -> Result<Response<HashGetResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        let request = request.into_inner();
+        let fields = self.cache.hash_get(&request.key, &request.fields)?;
+        Ok(Response::new(HashGetResponse {
+            fields: fields
+                .into_iter()
+                .map(|(field, value)| (field, value.to_vec()))
+                .collect(),
+        }))
+    }
+
+    async fn hash_delete(
+        &self,
+        request: Request<HashFieldsRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .hash_delete(self.security.peer_request(HashFieldsRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let (removed, version) = self.cache.hash_delete(&request.key, &request.fields)?;
+        Ok(Response::new(StructureResponse {
+            count: removed as u64,
+            version,
+        }))
+    }
+
+    async fn list_push(
+        &self,
+        request: Request<ListPushRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .list_push(self.security.peer_request(ListPushRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let values = request.values.into_iter().map(Bytes::from).collect();
+        let (length, version) = self.cache.list_push(
+            &request.key,
+            values,
+            request.front,
+            request_ttl(request.ttl),
+        )?;
+        Ok(Response::new(StructureResponse {
+            count: length as u64,
+            version,
+        }))
+    }
+
+    async fn list_pop(
+        &self,
+        request: Request<ListPopRequest>,
+    ) -> Result<Response<ValuesResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .list_pop(self.security.peer_request(ListPopRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let count = request.count.unwrap_or(1) as usize;
+        let (values, _) = self.cache.list_pop(&request.key, count, request.front)?;
+        Ok(Response::new(ValuesResponse {
+            values: values.into_iter().map(|value| value.to_vec()).collect(),
+        }))
+    }
+
+    async fn list_range(
+        &self,
+        request: Request<ListRangeRequest>,
+    ) -> Result<Response<ValuesResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        let request = request.into_inner();
+        let values = self
+            .cache
+            .list_range(&request.key, request.start, request.stop)?;
+        Ok(Response::new(ValuesResponse {
+            values: values.into_iter().map(|value| value.to_vec()).collect(),
+        }))
+    }
+
+    async fn set_add(
+        &self,
+        request: Request<SetMembersRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .set_add(self.security.peer_request(SetMembersRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let members = request.members.into_iter().map(Bytes::from).collect();
+        let (added, version) =
+            self.cache
+                .set_add(&request.key, members, request_ttl(request.ttl))?;
+        Ok(Response::new(StructureResponse {
+            count: added as u64,
+            version,
+        }))
+    }
+
+    async fn set_remove(
+        &self,
+        request: Request<SetMembersRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .set_remove(self.security.peer_request(SetMembersRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let members: Vec<Bytes> = request.members.into_iter().map(Bytes::from).collect();
+        let (removed, version) = self.cache.set_remove(&request.key, &members)?;
+        Ok(Response::new(StructureResponse {
+            count: removed as u64,
+            version,
+        }))
+    }
+
+    async fn set_members(
+        &self,
+        request: Request<CacheKey>,
+    ) -> Result<Response<ValuesResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        let members = self.cache.set_members(&request.into_inner().key)?;
+        Ok(Response::new(ValuesResponse {
+            values: members.into_iter().map(|member| member.to_vec()).collect(),
+        }))
+    }
+
+    async fn sorted_set_add(
+        &self,
+        request: Request<SortedSetAddRequest>,
+    ) -> Result<Response<StructureResponse>, Status> {
+        self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+
+        let request = request.into_inner();
+        if let Some(mut owner) = self
+            .owner_of(&request.key, peer && request.forwarded)
+            .await?
+        {
+            return owner
+                .sorted_set_add(self.security.peer_request(SortedSetAddRequest {
+                    forwarded: true,
+                    ..request
+                }))
+                .await;
+        }
+        let members = request
+            .members
+            .into_iter()
+            .map(|member| (Bytes::from(member.member), member.score))
+            .collect();
+        let (added, version) =
+            self.cache
+                .sorted_set_add(&request.key, members, request_ttl(request.ttl))?;
+        Ok(Response::new(StructureResponse {
+            count: added as u64,
+            version,
+        }))
+    }
+
+    async fn sorted_set_range_by_score(
+        &self,
+        request: Request<ScoreRangeRequest>,
+    ) -> Result<Response<ScoredMembers>, Status> {
+        self.security.authenticate(&request)?;
+
+        let request = request.into_inner();
+        let limit = request.limit.map_or(usize::MAX, |limit| limit as usize);
+        let members =
+            self.cache
+                .sorted_set_range_by_score(&request.key, request.min, request.max, limit)?;
+        Ok(Response::new(ScoredMembers {
+            members: members
+                .into_iter()
+                .map(|(member, score)| ScoredMember {
+                    member: member.to_vec(),
+                    score,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn sorted_set_rank(
+        &self,
+        request: Request<SortedSetRankRequest>,
+    ) -> Result<Response<SortedSetRankResponse>, Status> {
+        self.security.authenticate(&request)?;
+
+        let request = request.into_inner();
+        let response = match self.cache.sorted_set_rank(&request.key, &request.member)? {
+            Some((rank, score)) => SortedSetRankResponse {
+                found: true,
+                rank: rank as u64,
+                score,
+            },
+            None => SortedSetRankResponse::default(),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn acquire_lock(
+        &self,
+        request: Request<LockRequest>,
+    ) -> Result<Response<LockResponse>, Status> {
+        let client = self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+        let response = self
+            .locks
+            .handle(LockAction::Acquire, request.into_inner(), client, peer)
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn renew_lease(
+        &self,
+        request: Request<LockRequest>,
+    ) -> Result<Response<LockResponse>, Status> {
+        let client = self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+        let response = self
+            .locks
+            .handle(LockAction::Renew, request.into_inner(), client, peer)
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn release_lock(
+        &self,
+        request: Request<LockRequest>,
+    ) -> Result<Response<LockResponse>, Status> {
+        let client = self.security.authenticate(&request)?;
+        let peer = self.security.is_peer(&request);
+        let response = self
+            .locks
+            .handle(LockAction::Release, request.into_inner(), client, peer)
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn lock_released(
+        &self,
+        request: Request<LockReleasedRequest>,
+    ) -> Result<Response<LockReleasedResponse>, Status> {
+        self.security.authenticate_peer(&request)?;
+
+        for key in request.into_inner().keys {
+            self.locks.announced(&key);
         }
+        Ok(Response::new(LockReleasedResponse {}))
     }
 }
@@ -1,14 +1,25 @@
-use prometheus::{Encoder, IntCounterVec, Opts, Registry, TextEncoder};
-use serde_json::json;
+use prometheus::core::Collector;
+use prometheus::{Encoder, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tracing::info;
+use tracing::{info, warn};
+use warp::http::StatusCode;
 use warp::Filter;
 
+use crate::proto::TransactionInfo;
+use crate::security::is_admin_token;
+use crate::transaction_manager::TransactionError;
+
 pub struct Monitoring {
     pub registry: Registry,
     pub cache_hits: IntCounterVec,
     pub cache_misses: IntCounterVec,
+    pub read_repairs: IntCounterVec,
+    pub anti_entropy_repairs: IntCounterVec,
+    pub member_transitions: IntCounterVec,
+    pub active_transactions: IntGauge,
+    pub transactions: IntCounterVec,
 }
 
 impl Monitoring {
@@ -22,27 +33,70 @@ impl Monitoring {
             &["method"],
         )
         .unwrap();
+        let read_repairs = IntCounterVec::new(
+            Opts::new("read_repairs", "Number of replicas repaired on read"),
+            &["reason"],
+        )
+        .unwrap();
+        let anti_entropy_repairs = IntCounterVec::new(
+            Opts::new(
+                "anti_entropy_repairs",
+                "Number of keys synchronised by anti-entropy",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let member_transitions = IntCounterVec::new(
+            Opts::new(
+                "member_transitions",
+                "Number of members the failure detector moved to a state",
+            ),
+            &["state"],
+        )
+        .unwrap();
+
+        let active_transactions =
+            IntGauge::new("active_transactions", "Number of open transactions").unwrap();
+        let transactions = IntCounterVec::new(
+            Opts::new("transactions", "Number of finished transactions"),
+            &["outcome"],
+        )
+        .unwrap();
 
         registry.register(Box::new(cache_hits.clone())).unwrap();
         registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(read_repairs.clone())).unwrap();
+        registry
+            .register(Box::new(anti_entropy_repairs.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(member_transitions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_transactions.clone()))
+            .unwrap();
+        registry.register(Box::new(transactions.clone())).unwrap();
 
         Self {
             registry,
             cache_hits,
             cache_misses,
+            read_repairs,
+            anti_entropy_repairs,
+            member_transitions,
+            active_transactions,
+            transactions,
         }
     }
 
     pub fn serve(
-        self,
+        &self,
         cache: Arc<crate::cache::Cache>,
-        hasher: Arc<crate::hashing::ConsistentHashing>,
+        cluster_views: Arc<crate::cluster_view::ClusterViews>,
+        search_index: Arc<crate::search_index::SearchIndex>,
         config: Arc<crate::config::Config>,
     ) {
         let registry = self.registry.clone();
-        let cache_clone = cache.clone();
-        let hasher_clone = hasher.clone();
-        let config_clone = config.clone();
 
         // Metrics route
         let metrics_registry = registry.clone();
@@ -57,12 +111,14 @@ impl Monitoring {
 
         // Stats route
         let cache_clone_stats = cache.clone();
+        let cache_hits = self.cache_hits.clone();
+        let cache_misses = self.cache_misses.clone();
         let stats_route = warp::path("stats").map(move || {
             let current_memory = cache_clone_stats.get_current_memory();
             let entry_count = cache_clone_stats.data.len();
             let response = json!({
-                "cache_hits": 0, // Replace with actual metrics
-                "cache_misses": 0, // Replace with actual metrics
+                "cache_hits": total(&cache_hits),
+                "cache_misses": total(&cache_misses),
                 "memory_usage": current_memory,
                 "entry_count": entry_count,
             });
@@ -70,11 +126,8 @@ impl Monitoring {
         });
 
         // Nodes route
-        let hasher_clone_nodes = hasher.clone();
-        let nodes_route = warp::path("nodes").map(move || {
-            let nodes = hasher_clone_nodes.get_all_nodes();
-            warp::reply::json(&nodes)
-        });
+        let nodes_route =
+            warp::path("nodes").map(move || warp::reply::json(&cluster_views.to_json()));
 
         // Memory usage route
         let memory_route = warp::path("memory_usage").map(move || {
@@ -109,7 +162,6 @@ impl Monitoring {
         });
 
         // Update configuration route
-        let config_clone_update = config.clone();
         let config_update_route = warp::path("update_config")
             .and(warp::post())
             .and(warp::body::json())
@@ -127,7 +179,9 @@ impl Monitoring {
             .and(warp::get())
             .and(warp::query::<HashMap<String, String>>())
             .map(move |params: HashMap<String, String>| {
-                if let Some(key) = params.get("key") {
+                if let Some(query) = params.get("query") {
+                    warp::reply::json(&json!({ "keys": search_index.search(query) }))
+                } else if let Some(key) = params.get("key") {
                     if let Some(value) = cache_clone_search.get(key) {
                         let response = json!({
                             "found": true,
@@ -139,7 +193,60 @@ impl Monitoring {
                         warp::reply::json(&response)
                     }
                 } else {
-                    warp::reply::json(&json!({ "error": "Key parameter is missing" }))
+                    warp::reply::json(&json!({ "error": "Key or query parameter is missing" }))
+                }
+            });
+
+        // Transaction routes
+        let cache_clone_transactions = cache.clone();
+        let transactions_route = warp::path!("transactions").and(warp::get()).map(move || {
+            let transactions: Vec<Value> = cache_clone_transactions
+                .transaction_manager
+                .list()
+                .iter()
+                .map(transaction_json)
+                .collect();
+            warp::reply::json(&transactions)
+        });
+
+        let cache_clone_transaction = cache.clone();
+        let transaction_route = warp::path!("transactions" / String).and(warp::get()).map(
+            move |transaction_id: String| match cache_clone_transaction
+                .transaction_manager
+                .describe(&transaction_id)
+            {
+                Ok(info) => warp::reply::with_status(
+                    warp::reply::json(&transaction_json(&info)),
+                    StatusCode::OK,
+                ),
+                Err(e) => error_reply(e),
+            },
+        );
+
+        let cache_clone_abort = cache.clone();
+        let config_clone_abort = config.clone();
+        let abort_route = warp::path!("transactions" / String / "abort")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("x-admin-token"))
+            .map(move |transaction_id: String, token: Option<String>| {
+                if !is_admin_token(config_clone_abort.admin_token.as_deref(), token.as_deref()) {
+                    return warp::reply::with_status(
+                        warp::reply::json(&json!({ "error": "Admin token required" })),
+                        StatusCode::FORBIDDEN,
+                    );
+                }
+                match cache_clone_abort.abort_transaction(&transaction_id) {
+                    Ok(discarded) => {
+                        warn!(
+                            "Transaction ID: {} aborted from the dashboard ({} writes discarded)",
+                            transaction_id, discarded
+                        );
+                        warp::reply::with_status(
+                            warp::reply::json(&json!({ "status": "aborted" })),
+                            StatusCode::OK,
+                        )
+                    }
+                    Err(e) => error_reply(e),
                 }
             });
 
@@ -151,14 +258,54 @@ impl Monitoring {
             .or(config_route)
             .or(config_update_route)
             .or(search_cache_route)
+            .or(transactions_route)
+            .or(transaction_route)
+            .or(abort_route)
             .with(
                 warp::cors()
                     .allow_any_origin()
                     .allow_methods(vec!["GET", "POST"])
-                    .allow_headers(vec!["Content-Type"]),
+                    .allow_headers(vec!["Content-Type", "X-Admin-Token"]),
             );
 
         // Start the Warp server
         tokio::spawn(warp::serve(routes).run(([0, 0, 0, 0], 9898)));
     }
 }
+
+/// Sum of a counter over all of its labels.
+fn total(counter: &IntCounterVec) -> u64 {
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
+
+fn transaction_json(info: &TransactionInfo) -> Value {
+    json!({
+        "id": info.transaction_id,
+        "client": info.client,
+        "isolation": info.isolation,
+        "age_ms": info.age_ms,
+        "expires_in_ms": info.expires_in_ms,
+        "expired": info.expired,
+        "operations": info.operations,
+        "written_keys": info.written_keys,
+        "watched_keys": info.watched_keys,
+    })
+}
+
+fn error_reply(error: TransactionError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match error {
+        TransactionError::NotFound(_) => StatusCode::NOT_FOUND,
+        TransactionError::Expired(_) => StatusCode::GONE,
+        TransactionError::Disabled => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::CONFLICT,
+    };
+    warp::reply::with_status(
+        warp::reply::json(&json!({ "error": error.to_string() })),
+        status,
+    )
+}
@@ -0,0 +1,87 @@
+// src/node_identity.rs
+
+use std::net::SocketAddr;
+
+use tracing::warn;
+
+use crate::config::Config;
+use crate::hashing::Topology;
+
+/// Port peers listen on when neither the configuration nor the pod spec says
+/// otherwise.
+const DEFAULT_PORT: u16 = 50051;
+
+/// Who this node is. `address` is the canonical name of the node in the ring,
+/// a full endpoint such as `https://10.0.0.7:50051`, and must match what
+/// discovery reports for this pod so that the node recognizes itself among
+/// the members; `id` is a stable human-readable name.
+#[derive(Debug, Clone)]
+pub struct NodeIdentity {
+    pub id: String,
+    pub address: String,
+    /// `https` when TLS is configured. Every member of a cluster is expected
+    /// to use the same scheme.
+    pub scheme: &'static str,
+    /// The port this node serves on, and the default port of peers.
+    pub port: u16,
+    pub topology: Topology,
+    /// The ring weight this node advertises to its peers.
+    pub weight: f64,
+}
+
+impl NodeIdentity {
+    /// Resolves the identity from the configuration, which takes `POD_IP` and
+    /// `POD_NAME` from the downward API unless `NODE_ADDRESS` and `NODE_ID`
+    /// are set. Without either, the host falls back to the host part of
+    /// `LOCAL_ADDRESS` and the ID to the address.
+    pub fn from_config(config: &Config) -> Self {
+        let local = config.local_address.parse::<SocketAddr>().ok();
+        let port = local.map_or(DEFAULT_PORT, |addr| addr.port());
+        let scheme = if config.tls_cert_path.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let host = config.node_address.clone().unwrap_or_else(|| match local {
+            Some(addr) if !addr.ip().is_unspecified() => addr.ip().to_string(),
+            _ => {
+                warn!(
+                    "No node address configured (set POD_IP or NODE_ADDRESS); \
+                     using 127.0.0.1, which only works for a single node"
+                );
+                "127.0.0.1".to_string()
+            }
+        });
+        let address = endpoint(scheme, &host, port);
+        let id = config.node_id.clone().unwrap_or_else(|| address.clone());
+
+        Self {
+            id,
+            address,
+            scheme,
+            port,
+            topology: Topology {
+                zone: config.zone.clone(),
+                host: config.host_node.clone(),
+            },
+            weight: config.node_weight,
+        }
+    }
+
+    /// Returns the endpoint of a peer at `host`, on `port` if it is known.
+    pub fn peer_endpoint(&self, host: &str, port: Option<u16>) -> String {
+        endpoint(self.scheme, host, port.unwrap_or(self.port))
+    }
+
+    pub fn is_self(&self, node: &str) -> bool {
+        node == self.address
+    }
+}
+
+fn endpoint(scheme: &str, host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("{}://[{}]:{}", scheme, host, port)
+    } else {
+        format!("{}://{}:{}", scheme, host, port)
+    }
+}
@@ -0,0 +1,377 @@
+// src/placement.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+
+use crate::hashing::{HashAlgorithm, Ring};
+
+/// Decides which members own a key, given the key's position on the ring.
+pub trait Placement: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns up to `n` distinct owners of `token`, primary first.
+    fn owners(&self, ring: &Ring, token: u64, n: usize) -> Vec<String>;
+
+    /// Whether ownership is constant between consecutive ring tokens, so that
+    /// it can be reasoned about in token ranges instead of per key.
+    fn ranged(&self) -> bool {
+        false
+    }
+}
+
+/// Selects a `Placement` in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlacementAlgorithm {
+    Ring,
+    Rendezvous,
+    Jump,
+    Bounded,
+}
+
+impl PlacementAlgorithm {
+    pub fn build(self, algorithm: HashAlgorithm, load_factor: f64) -> Box<dyn Placement> {
+        match self {
+            PlacementAlgorithm::Ring => Box::new(RingPlacement),
+            PlacementAlgorithm::Rendezvous => Box::new(RendezvousPlacement { algorithm }),
+            PlacementAlgorithm::Jump => Box::new(JumpPlacement),
+            PlacementAlgorithm::Bounded => Box::new(BoundedLoadPlacement::new(load_factor)),
+        }
+    }
+}
+
+impl FromStr for PlacementAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ring" => Ok(PlacementAlgorithm::Ring),
+            "rendezvous" | "hrw" => Ok(PlacementAlgorithm::Rendezvous),
+            "jump" => Ok(PlacementAlgorithm::Jump),
+            "bounded" => Ok(PlacementAlgorithm::Bounded),
+            other => Err(format!("Unknown placement algorithm: {}", other)),
+        }
+    }
+}
+
+/// Classic consistent hashing: the first distinct members clockwise from the
+/// key's token on the virtual-node ring.
+pub struct RingPlacement;
+
+impl Placement for RingPlacement {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn owners(&self, ring: &Ring, token: u64, n: usize) -> Vec<String> {
+        ring.walk(token, n)
+    }
+
+    fn ranged(&self) -> bool {
+        true
+    }
+}
+
+/// Rendezvous (highest random weight) hashing: every member scores the key and
+/// the highest scores win. Only keys owned by a departing member move. Scores
+/// use the logarithmic method, `-weight / ln(h)` with `h` mapped into (0, 1],
+/// so a member's share of keys is proportional to its weight.
+pub struct RendezvousPlacement {
+    algorithm: HashAlgorithm,
+}
+
+impl Placement for RendezvousPlacement {
+    fn name(&self) -> &'static str {
+        "rendezvous"
+    }
+
+    fn owners(&self, ring: &Ring, token: u64, n: usize) -> Vec<String> {
+        let mut scored: Vec<(f64, &String)> = ring
+            .nodes
+            .iter()
+            .map(|node| {
+                let mut bytes = Vec::with_capacity(node.len() + 8);
+                bytes.extend_from_slice(node.as_bytes());
+                bytes.extend_from_slice(&token.to_le_bytes());
+                let h = (self.algorithm.hash(&bytes) as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                (-ring.weight(node) / h.ln(), node)
+            })
+            .collect();
+        scored.sort_unstable_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        scored
+            .into_iter()
+            .take(n)
+            .map(|(_, node)| node.clone())
+            .collect()
+    }
+}
+
+/// Jump consistent hashing (Lamping and Veach) over the sorted member list,
+/// with replicas on the following buckets. It needs no ring state, but members
+/// are addressed by position, so removing any member other than the last one
+/// moves more keys than the other placements. Weights are ignored.
+pub struct JumpPlacement;
+
+impl JumpPlacement {
+    fn bucket(mut key: u64, buckets: usize) -> usize {
+        let mut b: i64 = -1;
+        let mut j: i64 = 0;
+        while j < buckets as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+        b as usize
+    }
+}
+
+impl Placement for JumpPlacement {
+    fn name(&self) -> &'static str {
+        "jump"
+    }
+
+    fn owners(&self, ring: &Ring, token: u64, n: usize) -> Vec<String> {
+        let buckets = ring.nodes.len();
+        if buckets == 0 {
+            return Vec::new();
+        }
+        let first = Self::bucket(token, buckets);
+        (0..n.min(buckets))
+            .map(|i| ring.nodes[(first + i) % buckets].clone())
+            .collect()
+    }
+}
+
+/// Consistent hashing with bounded loads (Mirrokni et al.), where the load is
+/// each member's share of the key space: arcs of the ring are handed out
+/// largest first, each to the first member clockwise whose share would stay
+/// within `(1 + factor)` times its weighted share, so the arcs of a member
+/// that drew too many spill over to the next one. Ownership only depends on
+/// membership, so every node computes the same owners and changes move keys
+/// like any other membership change.
+pub struct BoundedLoadPlacement {
+    factor: f64,
+    primaries: Mutex<Vec<(u64, Arc<Primaries>)>>,
+}
+
+/// The primary owner of the arc ending at each token of a ring.
+type Primaries = BTreeMap<u64, String>;
+
+/// Ring generations whose arc assignment is kept, so that comparing the ring
+/// before and after a change does not recompute it for every token.
+const CACHED_RINGS: usize = 2;
+
+impl BoundedLoadPlacement {
+    pub fn new(factor: f64) -> Self {
+        Self {
+            factor,
+            primaries: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn primaries(&self, ring: &Ring) -> Arc<Primaries> {
+        let mut cached = self.primaries.lock().unwrap();
+        if let Some((_, primaries)) = cached.iter().find(|(g, _)| *g == ring.generation) {
+            return primaries.clone();
+        }
+        let primaries = Arc::new(self.assign(ring));
+        if cached.len() >= CACHED_RINGS {
+            cached.remove(0);
+        }
+        cached.push((ring.generation, primaries.clone()));
+        primaries
+    }
+
+    fn assign(&self, ring: &Ring) -> Primaries {
+        let tokens: Vec<(u64, &String)> = ring.tokens.iter().map(|(t, n)| (*t, n)).collect();
+        let mut arcs: Vec<(u64, usize)> = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, (token, _))| {
+                let previous = tokens[(i + tokens.len() - 1) % tokens.len()].0;
+                let length = match token.wrapping_sub(previous) {
+                    0 => u64::MAX,
+                    length => length,
+                };
+                (length, i)
+            })
+            .collect();
+        arcs.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let total_weight: f64 = ring.nodes.iter().map(|node| ring.weight(node)).sum();
+        let capacity =
+            |node: &str| ring.weight(node) / total_weight * (1.0 + self.factor) * u64::MAX as f64;
+        let mut used: HashMap<&str, f64> = HashMap::new();
+        let mut primaries = BTreeMap::new();
+        for (length, i) in arcs {
+            let length = length as f64;
+            let owner = (0..tokens.len())
+                .map(|step| tokens[(i + step) % tokens.len()].1)
+                .find(|node| {
+                    used.get(node.as_str()).copied().unwrap_or(0.0) + length <= capacity(node)
+                })
+                .unwrap_or(tokens[i].1);
+            *used.entry(owner).or_insert(0.0) += length;
+            primaries.insert(tokens[i].0, owner.clone());
+        }
+        primaries
+    }
+}
+
+impl Placement for BoundedLoadPlacement {
+    fn name(&self) -> &'static str {
+        "bounded"
+    }
+
+    fn owners(&self, ring: &Ring, token: u64, n: usize) -> Vec<String> {
+        let Some((&end, _)) = ring
+            .tokens
+            .range(token..)
+            .next()
+            .or_else(|| ring.tokens.iter().next())
+        else {
+            return Vec::new();
+        };
+        let primary = self.primaries(ring)[&end].clone();
+        let mut owners = vec![primary.clone()];
+        owners.extend(
+            ring.walk(end, n + 1)
+                .into_iter()
+                .filter(|node| *node != primary),
+        );
+        owners.truncate(n);
+        owners
+    }
+
+    fn ranged(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::hashing::ConsistentHashing;
+
+    const KEYS: usize = 20_000;
+    const ALGORITHMS: [PlacementAlgorithm; 4] = [
+        PlacementAlgorithm::Ring,
+        PlacementAlgorithm::Rendezvous,
+        PlacementAlgorithm::Jump,
+        PlacementAlgorithm::Bounded,
+    ];
+
+    fn cluster(algorithm: PlacementAlgorithm, nodes: usize) -> ConsistentHashing {
+        let hasher = ConsistentHashing::new(
+            100,
+            HashAlgorithm::XxHash64,
+            algorithm.build(HashAlgorithm::XxHash64, 0.25),
+            0,
+        );
+        for i in 0..nodes {
+            hasher.set_weight(format!("node-{}", i), 1.0);
+        }
+        hasher
+    }
+
+    fn primaries(hasher: &ConsistentHashing) -> Vec<String> {
+        (0..KEYS)
+            .map(|i| hasher.get_node(&format!("key-{}", i)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keys_spread_evenly() {
+        for algorithm in ALGORITHMS {
+            let hasher = cluster(algorithm, 5);
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for node in primaries(&hasher) {
+                *counts.entry(node).or_default() += 1;
+            }
+            assert_eq!(counts.len(), 5, "{:?}", algorithm);
+            let largest = *counts.values().max().unwrap() as f64 / (KEYS / 5) as f64;
+            assert!(
+                largest < 1.3,
+                "{:?} gives one node {:.2}x its share",
+                algorithm,
+                largest
+            );
+        }
+    }
+
+    #[test]
+    fn bounded_placement_caps_shares() {
+        let hasher = cluster(PlacementAlgorithm::Bounded, 5);
+        let ring = hasher.snapshot();
+        let mut shares: HashMap<String, f64> = HashMap::new();
+        for node in &ring.nodes {
+            let owned: f64 = hasher
+                .primary_ranges(&ring, node)
+                .into_iter()
+                .map(|(start, end)| end.wrapping_sub(start) as f64)
+                .sum();
+            shares.insert(node.clone(), owned / u64::MAX as f64);
+        }
+        for (node, share) in shares {
+            assert!(share <= 0.2 * 1.25 + 1e-9, "{} owns {:.3}", node, share);
+        }
+    }
+
+    #[test]
+    fn adding_a_node_moves_keys_only_to_it() {
+        for algorithm in ALGORITHMS {
+            let hasher = cluster(algorithm, 4);
+            let before = primaries(&hasher);
+            hasher.set_weight("node-4".to_string(), 1.0);
+            let after = primaries(&hasher);
+
+            let moved: Vec<_> = before
+                .iter()
+                .zip(&after)
+                .filter(|(before, after)| before != after)
+                .collect();
+            let fraction = moved.len() as f64 / KEYS as f64;
+            assert!(
+                fraction > 0.1 && fraction < 0.3,
+                "{:?} moved {:.2} of the keys",
+                algorithm,
+                fraction
+            );
+            if algorithm != PlacementAlgorithm::Bounded {
+                assert!(
+                    moved.iter().all(|(_, after)| *after == "node-4"),
+                    "{:?} moved keys between existing nodes",
+                    algorithm
+                );
+            }
+        }
+    }
+
+    /// Run with `cargo test benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark() {
+        for algorithm in ALGORITHMS {
+            for nodes in [5, 50] {
+                let hasher = cluster(algorithm, nodes);
+                let started = Instant::now();
+                let owners: usize = (0..KEYS)
+                    .map(|i| hasher.get_n_nodes(&format!("key-{}", i), 3).len())
+                    .sum();
+                let elapsed = started.elapsed();
+                assert_eq!(owners, KEYS * 3.min(nodes));
+                println!(
+                    "{:>10} with {:>2} nodes: {:>6} ns per lookup",
+                    hasher.placement().name(),
+                    nodes,
+                    elapsed.as_nanos() / KEYS as u128
+                );
+            }
+        }
+    }
+}
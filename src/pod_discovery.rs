@@ -1,75 +1,429 @@
 // src/pod_discovery.rs
 
+use async_trait::async_trait;
 use futures_util::StreamExt;
-use kube::api::{WatchEvent, WatchParams};
-use kube::{Api, Client};
-use std::sync::Arc;
-use tracing::{error, info};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::runtime::watcher::{self, Event};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client, ResourceExt};
+use std::collections::{HashMap, HashSet};
+use tracing::{error, info, warn};
 
-use crate::hashing::ConsistentHashing;
+use crate::discovery::{Discovery, Membership};
+use crate::hashing::Topology;
+use crate::node_identity::NodeIdentity;
 
+const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+
+/// Discovers the cache pods in `NAMESPACE` labelled `app=APP_LABEL`. The
+/// service account needs `get`, `list` and `watch` on pods in that namespace,
+/// and `get` on nodes, which are cluster-scoped, to look up the zone of pods
+/// that do not carry the zone label themselves. Without the node permission
+/// those pods simply have no zone.
 pub struct PodDiscovery {
     namespace: String,
     app_label: String,
-    hasher: Arc<ConsistentHashing>,
+    weight_annotation: String,
+    container_name: String,
+    port_name: String,
+    weight_memory_unit: u64,
+    /// Zone of each Kubernetes node, looked up once per node.
+    zones: HashMap<String, Option<String>>,
 }
 
 impl PodDiscovery {
-    pub fn new(hasher: Arc<ConsistentHashing>) -> Self {
+    pub fn new(weight_memory_unit: u64) -> Self {
         let namespace = std::env::var("NAMESPACE").unwrap_or_else(|_| "default".to_string());
         let app_label =
             std::env::var("APP_LABEL").unwrap_or_else(|_| "distributed-cache".to_string());
+        let weight_annotation = std::env::var("WEIGHT_ANNOTATION")
+            .unwrap_or_else(|_| "distributed-cache/weight".to_string());
+        let container_name =
+            std::env::var("CACHE_CONTAINER").unwrap_or_else(|_| "distributed-cache".to_string());
+        let port_name = std::env::var("PEER_PORT_NAME").unwrap_or_else(|_| "grpc".to_string());
 
         Self {
             namespace,
             app_label,
-            hasher,
+            weight_annotation,
+            container_name,
+            port_name,
+            weight_memory_unit,
+            zones: HashMap::new(),
         }
     }
 
-    pub async fn start(self) {
-        let client = match Client::try_default().await {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to create Kubernetes client: {}", e);
+    /// Whether a pod should be a member: it is Ready and not being deleted.
+    /// Pending pods and pods failing their readiness probe stay out of the ring.
+    fn is_eligible(pod: &Pod) -> bool {
+        if pod.metadata.deletion_timestamp.is_some() {
+            return false;
+        }
+        pod.status
+            .as_ref()
+            .and_then(|status| status.conditions.as_ref())
+            .is_some_and(|conditions| {
+                conditions
+                    .iter()
+                    .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+            })
+    }
+
+    /// Returns the endpoint peers reach a pod at: its IP and the cache
+    /// container's port named `port_name`, or its only port, or this node's port.
+    fn pod_endpoint(&self, pod: &Pod, identity: &NodeIdentity) -> Option<String> {
+        let pod_ip = pod.status.as_ref()?.pod_ip.as_ref()?;
+        let ports = pod
+            .spec
+            .as_ref()
+            .and_then(|spec| {
+                spec.containers
+                    .iter()
+                    .find(|container| container.name == self.container_name)
+            })
+            .and_then(|container| container.ports.as_ref());
+        let port = ports.and_then(|ports| {
+            ports
+                .iter()
+                .find(|port| port.name.as_deref() == Some(self.port_name.as_str()))
+                .or_else(|| (ports.len() == 1).then(|| &ports[0]))
+                .and_then(|port| u16::try_from(port.container_port).ok())
+        });
+        Some(identity.peer_endpoint(pod_ip, port))
+    }
+
+    /// Returns the ring weight set by a pod's weight annotation, if any.
+    fn annotated_weight(&self, pod: &Pod) -> Option<f64> {
+        let value = pod
+            .metadata
+            .annotations
+            .as_ref()?
+            .get(&self.weight_annotation)?;
+        match value.parse::<f64>() {
+            Ok(weight) if weight > 0.0 => Some(weight),
+            _ => {
+                warn!("Ignoring invalid weight annotation: {}", value);
+                None
+            }
+        }
+    }
+
+    /// Estimates the ring weight of a pod: its weight annotation if set,
+    /// otherwise the cache container's memory limit in units of
+    /// `weight_memory_unit`.
+    fn pod_weight(&self, pod: &Pod) -> Option<f64> {
+        if let Some(weight) = self.annotated_weight(pod) {
+            return Some(weight);
+        }
+
+        pod.spec
+            .as_ref()
+            .and_then(|spec| {
+                spec.containers
+                    .iter()
+                    .find(|container| container.name == self.container_name)
+            })
+            .and_then(|container| container.resources.as_ref())
+            .and_then(|resources| resources.limits.as_ref())
+            .and_then(|limits| limits.get("memory"))
+            .and_then(|quantity| parse_memory_quantity(&quantity.0))
+            .map(|bytes| bytes / self.weight_memory_unit as f64)
+            .filter(|weight| *weight > 0.0)
+    }
+
+    /// Returns the failure domains of a pod: the zone label of the pod, or of
+    /// the Kubernetes node it is scheduled on, and the node name.
+    async fn pod_topology(&mut self, nodes: &Api<Node>, pod: &Pod) -> Topology {
+        let host = pod.spec.as_ref().and_then(|spec| spec.node_name.clone());
+        let mut zone = pod.labels().get(ZONE_LABEL).cloned();
+
+        if zone.is_none() {
+            if let Some(host) = &host {
+                if !self.zones.contains_key(host) {
+                    let node_zone = match nodes.get(host).await {
+                        Ok(node) => node
+                            .metadata
+                            .labels
+                            .and_then(|mut labels| labels.remove(ZONE_LABEL)),
+                        Err(e) => {
+                            warn!("Failed to look up zone of node {}: {}", host, e);
+                            None
+                        }
+                    };
+                    self.zones.insert(host.clone(), node_zone);
+                }
+                zone = self.zones.get(host).cloned().flatten();
+            }
+        }
+
+        Topology { zone, host }
+    }
+
+    /// Keeps the ring in line with the cache pods visible through `client`.
+    /// The watcher re-lists after the watch expires or fails, retrying with
+    /// backoff, and is restarted when a resync is requested, so this only
+    /// returns if the stream ends.
+    pub async fn watch(mut self, client: Client, mut membership: Membership) {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let pods: Api<Pod> = Api::namespaced(client, &self.namespace);
+        let config = watcher::Config::default().labels(&format!("app={}", self.app_label));
+        let resync = membership.resync();
+        let mut stream = watcher::watcher(pods.clone(), config.clone())
+            .default_backoff()
+            .boxed();
+
+        loop {
+            let event = tokio::select! {
+                event = stream.next() => event,
+                _ = resync.notified() => {
+                    info!("Re-listing pods on request");
+                    stream = watcher::watcher(pods.clone(), config.clone())
+                        .default_backoff()
+                        .boxed();
+                    continue;
+                }
+            };
+            let Some(event) = event else {
+                break;
+            };
+            match event {
+                Ok(Event::Applied(pod)) => self.apply(&nodes, &mut membership, &pod).await,
+                Ok(Event::Deleted(pod)) => membership.leave(&pod.name_any()),
+                Ok(Event::Restarted(pods)) => {
+                    // Pods deleted while the watch was down never produce a
+                    // `Deleted` event, so anything not re-listed is gone
+                    let listed: HashSet<String> = pods.iter().map(|pod| pod.name_any()).collect();
+                    membership.retain(&listed);
+                    for pod in &pods {
+                        self.apply(&nodes, &mut membership, pod).await;
+                    }
+                    info!("Pod list resynced: {} members", membership.len());
+                }
+                Err(e) => {
+                    warn!("Pod watch failed, retrying: {}", e);
+                }
+            }
+        }
+        error!("Pod watch ended; membership is no longer updated");
+    }
+
+    /// Adds a pod to the ring, updates it, or removes it once it stops being
+    /// eligible.
+    async fn apply(&mut self, nodes: &Api<Node>, membership: &mut Membership, pod: &Pod) {
+        let name = pod.name_any();
+        let endpoint = match self.pod_endpoint(pod, membership.identity()) {
+            Some(endpoint) if Self::is_eligible(pod) => endpoint,
+            _ => {
+                membership.leave(&name);
                 return;
             }
         };
+        let topology = self.pod_topology(nodes, pod).await;
+        // This node's own weight only follows its annotation: without one, it
+        // keeps the weight it was configured with
+        let weight = if membership.identity().is_self(&endpoint) {
+            self.annotated_weight(pod)
+        } else {
+            self.pod_weight(pod)
+        };
+        membership.join(&name, endpoint, weight, topology);
+    }
+}
 
-        let pods: Api<k8s_openapi::api::core::v1::Pod> = Api::namespaced(client, &self.namespace);
-        let wp = WatchParams::default().labels(&format!("app={}", self.app_label));
+#[async_trait]
+impl Discovery for PodDiscovery {
+    fn name(&self) -> &'static str {
+        "kubernetes"
+    }
 
-        let mut stream = match pods.watch(&wp, "0").await {
-            Ok(watcher) => watcher.boxed(),
+    async fn run(self: Box<Self>, membership: Membership) {
+        let client = match Client::try_default().await {
+            Ok(client) => client,
             Err(e) => {
-                error!("Failed to watch pods: {}", e);
+                error!("Failed to create Kubernetes client: {}", e);
                 return;
             }
         };
+        self.watch(client, membership).await;
+    }
+}
 
-        while let Some(status) = stream.next().await {
-            match status {
-                Ok(WatchEvent::Added(pod)) | Ok(WatchEvent::Modified(pod)) => {
-                    if let Some(pod_ip) =
-                        pod.status.as_ref().and_then(|status| status.pod_ip.clone())
-                    {
-                        self.hasher.add_node(pod_ip.clone());
-                        info!("Pod added/modified: {}", pod_ip);
-                    }
-                }
-                Ok(WatchEvent::Deleted(pod)) => {
-                    if let Some(pod_ip) =
-                        pod.status.as_ref().and_then(|status| status.pod_ip.clone())
-                    {
-                        self.hasher.remove_node(&pod_ip);
-                        info!("Pod deleted: {}", pod_ip);
-                    }
-                }
-                Err(e) => {
-                    error!("Watch event error: {}", e);
+/// Parses a Kubernetes memory quantity such as `512Mi`, `1.5G` or `500m`
+/// into bytes.
+fn parse_memory_quantity(quantity: &str) -> Option<f64> {
+    const SUFFIXES: [(&str, f64); 13] = [
+        ("Ki", 1024.0),
+        ("Mi", 1048576.0),
+        ("Gi", 1073741824.0),
+        ("Ti", 1099511627776.0),
+        ("Pi", 1125899906842624.0),
+        ("Ei", 1152921504606846976.0),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+        ("m", 1e-3),
+    ];
+    let quantity = quantity.trim();
+    for (suffix, multiplier) in SUFFIXES {
+        if let Some(number) = quantity.strip_suffix(suffix) {
+            return number.parse::<f64>().ok().map(|n| n * multiplier);
+        }
+    }
+    quantity.parse::<f64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use tokio::sync::{mpsc, Notify};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use warp::Filter;
+
+    use super::*;
+    use crate::hashing::{ConsistentHashing, HashAlgorithm};
+    use crate::placement::PlacementAlgorithm;
+
+    fn pod(name: &str, ip: &str, ready: bool) -> Value {
+        json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": name,
+                "namespace": "default",
+                "resourceVersion": "1",
+                "labels": { "app": "distributed-cache" },
+            },
+            "spec": {
+                "nodeName": "worker-1",
+                "containers": [{
+                    "name": "distributed-cache",
+                    "ports": [{ "name": "grpc", "containerPort": 6000 }],
+                }],
+            },
+            "status": {
+                "podIP": ip,
+                "conditions": [{ "type": "Ready", "status": if ready { "True" } else { "False" } }],
+            },
+        })
+    }
+
+    /// Serves a pod list, then streams whatever the test sends as watch
+    /// events, and answers node lookups with a zone label.
+    async fn fake_api_server(pods: Vec<Value>) -> (String, mpsc::UnboundedSender<Value>) {
+        let (events, receiver) = mpsc::unbounded_channel::<Value>();
+        let receiver = Arc::new(Mutex::new(Some(receiver)));
+
+        let pods_route = warp::path!("api" / "v1" / "namespaces" / String / "pods")
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |_namespace: String, query: HashMap<String, String>| {
+                if query.get("watch").map(String::as_str) != Some("true") {
+                    let list = json!({
+                        "apiVersion": "v1",
+                        "kind": "PodList",
+                        "metadata": { "resourceVersion": "1" },
+                        "items": pods,
+                    });
+                    return warp::reply::Response::new(list.to_string().into());
                 }
-                _ => {}
+                // Later watches, after the first one ends, see no more events
+                let stream: futures_util::stream::BoxStream<'static, Result<String, Infallible>> =
+                    match receiver.lock().unwrap().take() {
+                        Some(receiver) => UnboundedReceiverStream::new(receiver)
+                            .map(|event| Ok(format!("{}\n", event)))
+                            .boxed(),
+                        None => futures_util::stream::pending().boxed(),
+                    };
+                warp::reply::Response::new(warp::hyper::Body::wrap_stream(stream))
+            });
+        let nodes_route = warp::path!("api" / "v1" / "nodes" / String).map(|name: String| {
+            warp::reply::json(&json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": { "name": name, "labels": { ZONE_LABEL: "zone-a" } },
+            }))
+        });
+
+        let (address, server) =
+            warp::serve(pods_route.or(nodes_route)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), events)
+    }
+
+    async fn wait_for(hasher: &ConsistentHashing, expected: &[&str]) {
+        let mut expected: Vec<String> = expected.iter().map(|node| node.to_string()).collect();
+        expected.sort();
+        for _ in 0..100 {
+            let mut members = hasher.members();
+            members.sort();
+            if members == expected {
+                return;
             }
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+        panic!(
+            "members are {:?}, expected {:?}",
+            hasher.members(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_follows_pods_on_the_api_server() {
+        let (url, events) = fake_api_server(vec![
+            pod("cache-0", "10.0.0.1", true),
+            pod("cache-1", "10.0.0.2", false),
+        ])
+        .await;
+        let client = Client::try_from(kube::Config::new(url.parse().unwrap())).unwrap();
+
+        let hasher = Arc::new(ConsistentHashing::new(
+            100,
+            HashAlgorithm::XxHash64,
+            PlacementAlgorithm::Ring.build(HashAlgorithm::XxHash64, 0.25),
+            0,
+        ));
+        let identity = NodeIdentity {
+            id: "cache-9".to_string(),
+            address: "http://10.0.0.9:6000".to_string(),
+            scheme: "http",
+            port: 50051,
+            topology: Topology::default(),
+            weight: 1.0,
+        };
+        let membership = Membership::new(hasher.clone(), identity, Arc::new(Notify::new()));
+        tokio::spawn(PodDiscovery::new(1).watch(client, membership));
+
+        // Only the ready pod joins, on its named port, in its node's zone
+        wait_for(&hasher, &["http://10.0.0.1:6000"]).await;
+        assert_eq!(
+            hasher.zone_of("http://10.0.0.1:6000").as_deref(),
+            Some("zone-a")
+        );
+
+        events
+            .send(json!({ "type": "MODIFIED", "object": pod("cache-1", "10.0.0.2", true) }))
+            .unwrap();
+        wait_for(&hasher, &["http://10.0.0.1:6000", "http://10.0.0.2:6000"]).await;
+
+        events
+            .send(json!({ "type": "DELETED", "object": pod("cache-0", "10.0.0.1", true) }))
+            .unwrap();
+        wait_for(&hasher, &["http://10.0.0.2:6000"]).await;
+    }
+
+    #[test]
+    fn parses_memory_quantities() {
+        assert_eq!(parse_memory_quantity("512Mi"), Some(536870912.0));
+        assert_eq!(parse_memory_quantity("1.5G"), Some(1.5e9));
+        assert_eq!(parse_memory_quantity("1500m"), Some(1.5));
+        assert_eq!(parse_memory_quantity("2048"), Some(2048.0));
+        assert_eq!(parse_memory_quantity("lots"), None);
     }
 }
@@ -0,0 +1,136 @@
+// src/rebalancer.rs
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use crate::cache::Cache;
+use crate::hashing::{ConsistentHashing, Ring};
+use crate::replication::{Mutation, Replicator};
+
+/// Moves data to its new owners whenever ring membership changes, and drops
+/// local copies of keys this node stopped owning once the move is confirmed.
+/// Each moved key is sent by one node only: the first of its old owners that
+/// is still live. The other old owners drop their copies straight away, since
+/// the sender keeps its own until the new owners confirm it.
+pub struct Rebalancer {
+    cache: Arc<Cache>,
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    local_node_address: String,
+}
+
+impl Rebalancer {
+    pub fn new(
+        cache: Arc<Cache>,
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        local_node_address: String,
+    ) -> Self {
+        Self {
+            cache,
+            hasher,
+            replicator,
+            local_node_address,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut changes = self.hasher.subscribe();
+        let mut previous = self.hasher.snapshot();
+        while changes.changed().await.is_ok() {
+            let current = self.hasher.snapshot();
+            self.rebalance(&previous, &current).await;
+            previous = current;
+        }
+    }
+
+    async fn rebalance(&self, old: &Ring, new: &Ring) {
+        let replicas = self.replicator.replication_factor + 1;
+        info!(
+            "Ring membership changed from {} to {} members, rebalancing",
+            old.nodes.len(),
+            new.nodes.len()
+        );
+
+        let keys: Vec<String> = self
+            .cache
+            .data
+            .iter()
+            .map(|entry| entry.key().clone())
+            .chain(
+                self.cache
+                    .tombstones
+                    .iter()
+                    .map(|tombstone| tombstone.key().clone()),
+            )
+            .collect();
+
+        // Entries are grouped per new owner so each transfer goes out as
+        // batches on that owner's replication stream.
+        let mut transfers: HashMap<String, Vec<Mutation>> = HashMap::new();
+        let mut handoffs: HashMap<String, bool> = HashMap::new();
+        for key in keys {
+            let token = self.hasher.key_token(&key);
+            let old_owners = self.hasher.owners_in(old, token, replicas);
+            let new_owners = self.hasher.owners_in(new, token, replicas);
+            if old_owners == new_owners {
+                continue;
+            }
+            let Some(mutation) = self.cache.replica_state(&key) else {
+                continue;
+            };
+
+            let sender = old_owners
+                .iter()
+                .find(|node| new.nodes.contains(node))
+                .or(old_owners.first());
+            if sender == Some(&self.local_node_address) {
+                for node in &new_owners {
+                    if *node == self.local_node_address || old_owners.contains(node) {
+                        continue;
+                    }
+                    transfers
+                        .entry(node.clone())
+                        .or_default()
+                        .push(mutation.clone());
+                }
+            }
+            if !new_owners.contains(&self.local_node_address) {
+                handoffs.insert(key, true);
+            }
+        }
+
+        let mut moved = 0usize;
+        for (node, mutations) in transfers {
+            let keys: Vec<String> = mutations.iter().map(|m| m.key().to_string()).collect();
+            let results = self.replicator.send_all(&node, mutations).await;
+            for (key, confirmed) in keys.into_iter().zip(results) {
+                if confirmed {
+                    moved += 1;
+                } else if let Some(handoff) = handoffs.get_mut(&key) {
+                    *handoff = false;
+                }
+            }
+        }
+
+        let mut dropped = 0usize;
+        for (key, confirmed) in handoffs {
+            if confirmed {
+                self.cache.discard(&key);
+                dropped += 1;
+            } else {
+                warn!(
+                    "Keeping key {} until its new owners confirm the transfer",
+                    key
+                );
+            }
+        }
+
+        info!(
+            "Rebalance finished: {} entries transferred, {} entries dropped",
+            moved, dropped
+        );
+    }
+}
@@ -1,18 +1,309 @@
 //replication.rs
 
 use bytes::Bytes;
+use dashmap::DashMap;
+use futures_util::future::join_all;
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::counter::PnCounter;
 use crate::hashing::ConsistentHashing;
+use crate::monitoring::Monitoring;
 use crate::proto::cache_service_client::CacheServiceClient;
-use crate::proto::CacheEntry;
-use tonic::transport::Channel;
-use tracing::error;
+use crate::proto::{CacheKey, NodeInfo, ReplicationBatch, ReplicationRequest};
+use crate::security::peer_request;
+use crate::structures::{Delta, Structure};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tracing::{error, info, warn};
+
+/// Maximum number of mutations sent to a peer in one `ReplicationBatch`.
+const BATCH_SIZE: usize = 256;
+/// Mutations queued per peer. Once it is full, replication of new writes skips
+/// the peer and acknowledged sends wait.
+const PEER_QUEUE_SIZE: usize = 4096;
+/// Batches sent to a peer without an acknowledgement before sending pauses.
+const MAX_IN_FLIGHT: usize = 8;
+/// Times in a row a peer's replication stream is opened before its queued
+/// mutations are given up on.
+const REPLICATION_ATTEMPTS: u32 = 3;
+/// Wait before reopening a stream, multiplied by the number of failed attempts.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A change to the cache that has to reach every replica of its key. Values are
+/// LZ4-compressed exactly as the cache stores them, so they can be shipped
+/// without being decompressed and compressed again.
+#[derive(Debug, Clone)]
+pub enum Mutation {
+    Put {
+        key: String,
+        value: Bytes,
+        ttl: Option<Duration>,
+        version: u64,
+    },
+    Delete {
+        key: String,
+        version: u64,
+    },
+    /// The full state of a counter, merged rather than replaced on arrival.
+    /// `version` is that of the latest update that reached it.
+    Counter {
+        key: String,
+        counter: PnCounter,
+        ttl: Option<Duration>,
+        version: u64,
+    },
+    /// The whole of a hash, list, set or sorted set, which replaces the
+    /// replica's copy like a put.
+    Structure {
+        key: String,
+        structure: Structure,
+        ttl: Option<Duration>,
+        version: u64,
+    },
+    /// An update to a structure, made against the replica's copy at version
+    /// `previous`. The structure keeps its expiry.
+    StructureDelta {
+        key: String,
+        delta: Delta,
+        previous: u64,
+        version: u64,
+    },
+}
+
+impl Mutation {
+    /// Builds a put from an uncompressed value.
+    pub fn put(key: String, value: &[u8], ttl: Option<Duration>, version: u64) -> Self {
+        Mutation::Put {
+            key,
+            value: crate::cache::encode(value),
+            ttl,
+            version,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Mutation::Put { key, .. }
+            | Mutation::Delete { key, .. }
+            | Mutation::Counter { key, .. }
+            | Mutation::Structure { key, .. }
+            | Mutation::StructureDelta { key, .. } => key,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        match self {
+            Mutation::Put { version, .. }
+            | Mutation::Delete { version, .. }
+            | Mutation::Counter { version, .. }
+            | Mutation::Structure { version, .. }
+            | Mutation::StructureDelta { version, .. } => *version,
+        }
+    }
+
+    /// The same mutation under another version.
+    pub fn with_version(mut self, new_version: u64) -> Self {
+        match &mut self {
+            Mutation::Put { version, .. }
+            | Mutation::Delete { version, .. }
+            | Mutation::Counter { version, .. }
+            | Mutation::Structure { version, .. }
+            | Mutation::StructureDelta { version, .. } => *version = new_version,
+        }
+        self
+    }
+
+    /// How long the written value lives; `None` for deletes, deltas and
+    /// values that never expire.
+    pub fn ttl(&self) -> Option<Duration> {
+        match self {
+            Mutation::Put { ttl, .. }
+            | Mutation::Counter { ttl, .. }
+            | Mutation::Structure { ttl, .. } => *ttl,
+            Mutation::Delete { .. } | Mutation::StructureDelta { .. } => None,
+        }
+    }
+
+    pub fn to_request(&self) -> ReplicationRequest {
+        match self {
+            Mutation::Put {
+                key,
+                value,
+                ttl,
+                version,
+            } => ReplicationRequest {
+                key: key.clone(),
+                value: value.to_vec(),
+                ttl: ttl.map_or(0, |t| t.as_secs_f64().ceil() as i64),
+                version: *version,
+                tombstone: false,
+                compressed: true,
+                counter: None,
+                structure: vec![],
+                structure_delta: vec![],
+                previous_version: 0,
+            },
+            Mutation::Delete { key, version } => ReplicationRequest {
+                key: key.clone(),
+                value: vec![],
+                ttl: 0,
+                version: *version,
+                tombstone: true,
+                compressed: false,
+                counter: None,
+                structure: vec![],
+                structure_delta: vec![],
+                previous_version: 0,
+            },
+            Mutation::Counter {
+                key,
+                counter,
+                ttl,
+                version,
+            } => ReplicationRequest {
+                key: key.clone(),
+                value: counter.value().to_string().into_bytes(),
+                ttl: ttl.map_or(0, |t| t.as_secs_f64().ceil() as i64),
+                version: *version,
+                tombstone: false,
+                compressed: false,
+                counter: Some(counter.into()),
+                structure: vec![],
+                structure_delta: vec![],
+                previous_version: 0,
+            },
+            Mutation::Structure {
+                key,
+                structure,
+                ttl,
+                version,
+            } => ReplicationRequest {
+                key: key.clone(),
+                value: vec![],
+                ttl: ttl.map_or(0, |t| t.as_secs_f64().ceil() as i64),
+                version: *version,
+                tombstone: false,
+                compressed: false,
+                counter: None,
+                structure: serde_json::to_vec(structure).unwrap(),
+                structure_delta: vec![],
+                previous_version: 0,
+            },
+            Mutation::StructureDelta {
+                key,
+                delta,
+                previous,
+                version,
+            } => ReplicationRequest {
+                key: key.clone(),
+                value: vec![],
+                ttl: 0,
+                version: *version,
+                tombstone: false,
+                compressed: false,
+                counter: None,
+                structure: vec![],
+                structure_delta: serde_json::to_vec(delta).unwrap(),
+                previous_version: *previous,
+            },
+        }
+    }
+
+    /// Decodes the state returned by `ReadReplica`, where version 0 means the
+    /// replica has nothing for the key. A corrupt state counts as nothing, so
+    /// that read repair overwrites it.
+    pub fn from_replica_state(request: ReplicationRequest) -> Option<Self> {
+        if request.version == 0 {
+            None
+        } else {
+            Self::from_request(request)
+        }
+    }
+
+    /// Decodes a replicated mutation. Returns `None` when a compressed value
+    /// does not decompress or a delta does not parse, so that it is never
+    /// stored.
+    pub fn from_request(request: ReplicationRequest) -> Option<Self> {
+        Some(if request.tombstone {
+            Mutation::Delete {
+                key: request.key,
+                version: request.version,
+            }
+        } else {
+            let ttl = if request.ttl > 0 {
+                Some(Duration::from_secs(request.ttl as u64))
+            } else {
+                None
+            };
+            if let Some(counter) = request.counter {
+                Mutation::Counter {
+                    key: request.key,
+                    counter: counter.into(),
+                    ttl,
+                    version: request.version,
+                }
+            } else if !request.structure_delta.is_empty() {
+                Mutation::StructureDelta {
+                    key: request.key,
+                    delta: serde_json::from_slice(&request.structure_delta).ok()?,
+                    previous: request.previous_version,
+                    version: request.version,
+                }
+            } else if let Ok(structure) = serde_json::from_slice(&request.structure) {
+                Mutation::Structure {
+                    key: request.key,
+                    structure,
+                    ttl,
+                    version: request.version,
+                }
+            } else if request.compressed {
+                crate::cache::decode(&request.value)?;
+                Mutation::Put {
+                    key: request.key,
+                    value: Bytes::from(request.value),
+                    ttl,
+                    version: request.version,
+                }
+            } else {
+                Mutation::put(request.key, &request.value, ttl, request.version)
+            }
+        })
+    }
+}
+
+/// How a replication stream to a peer ended.
+enum Stream {
+    /// The queue was closed and everything in it was sent.
+    Drained,
+    /// The stream broke after the peer acknowledged at least one batch.
+    Progressed,
+    /// The stream could not be opened or broke before any acknowledgement.
+    Failed,
+}
+
+/// A mutation waiting in a peer's queue, with an optional channel notified
+/// once the peer has acknowledged the batch containing it.
+struct Outgoing {
+    mutation: Mutation,
+    ack: Option<oneshot::Sender<bool>>,
+}
 
 pub struct Replicator {
     hasher: Arc<ConsistentHashing>,
     pub replication_factor: usize,
     local_node_address: String,
+    monitoring: Arc<Monitoring>,
+    tls: Option<ClientTlsConfig>,
+    peers: DashMap<String, mpsc::Sender<Outgoing>>,
+    verified: DashMap<String, ()>,
+    /// Connections kept by `client` for reuse.
+    clients: DashMap<String, CacheServiceClient<Channel>>,
+    peer_token: Option<String>,
 }
 
 impl Replicator {
@@ -20,36 +311,402 @@ impl Replicator {
         hasher: Arc<ConsistentHashing>,
         replication_factor: usize,
         local_node_address: String,
+        monitoring: Arc<Monitoring>,
+        tls: Option<ClientTlsConfig>,
+        peer_token: Option<String>,
     ) -> Self {
         Self {
             hasher,
             replication_factor,
             local_node_address,
+            monitoring,
+            tls,
+            peers: DashMap::new(),
+            verified: DashMap::new(),
+            clients: DashMap::new(),
+            peer_token,
         }
     }
 
-    pub async fn replicate(&self, key: String, value: Bytes, ttl: i64) {
-        let nodes = self.hasher.get_n_nodes(&key, self.replication_factor + 1); // +1 to include local node
+    pub fn local_node(&self) -> &str {
+        &self.local_node_address
+    }
+
+    /// Wraps a request to another node, presenting the peer token so that
+    /// the node accepts it on RPCs only nodes may call.
+    pub fn peer_request<T>(&self, message: T) -> tonic::Request<T> {
+        peer_request(self.peer_token.as_deref(), message)
+    }
+
+    /// Describes how this node places keys, for the handshake with peers.
+    pub fn node_info(&self) -> NodeInfo {
+        let algorithm = self.hasher.algorithm();
+        NodeInfo {
+            hash_algorithm: algorithm.name().to_string(),
+            hash_fingerprint: algorithm.fingerprint(),
+        }
+    }
 
-        for node in nodes {
-            if node == self.local_node_address {
-                continue; // Skip local node
+    /// Connects to a peer by its endpoint, over TLS for `https` endpoints, where
+    /// the peer's certificate must name `TLS_SERVER_NAME` if set, or else the
+    /// endpoint's host.
+    /// The first connection to each peer performs a handshake, and peers that
+    /// place keys differently are refused.
+    pub async fn connect(&self, node: &str) -> Option<CacheServiceClient<Channel>> {
+        let mut endpoint = match Endpoint::from_shared(node.to_string()) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Invalid endpoint for node {}: {}", node, e);
+                return None;
             }
-            let addr = format!("http://{}:50051", node);
-            let mut client = match CacheServiceClient::connect(addr.clone()).await {
-                Ok(client) => client,
+        };
+        if node.starts_with("https://") {
+            let Some(tls) = &self.tls else {
+                error!("Node {} requires TLS, but TLS is not configured", node);
+                return None;
+            };
+            endpoint = match endpoint.tls_config(tls.clone()) {
+                Ok(endpoint) => endpoint,
                 Err(e) => {
-                    error!("Failed to connect to node {}: {}", addr, e);
-                    continue;
+                    error!("Invalid TLS configuration for node {}: {}", node, e);
+                    return None;
                 }
             };
-            let entry = CacheEntry {
-                key: key.clone(),
-                value: value.clone().to_vec(),
-                ttl,
+        }
+        let mut client = match endpoint.connect().await {
+            Ok(channel) => CacheServiceClient::new(channel),
+            Err(e) => {
+                error!("Failed to connect to node {}: {}", node, e);
+                return None;
+            }
+        };
+        if self.verified.contains_key(node) {
+            return Some(client);
+        }
+
+        let local = self.node_info();
+        match client.handshake(self.peer_request(local.clone())).await {
+            Ok(response) if response.get_ref() == &local => {
+                self.verified.insert(node.to_string(), ());
+                Some(client)
+            }
+            Ok(response) => {
+                error!(
+                    "Node {} uses hash algorithm {}, local is {}; refusing to replicate",
+                    node,
+                    response.get_ref().hash_algorithm,
+                    local.hash_algorithm
+                );
+                None
+            }
+            Err(e) => {
+                error!("Handshake with node {} failed: {}", node, e);
+                None
+            }
+        }
+    }
+
+    /// Like `connect`, but reuses the connection made the last time, which
+    /// reconnects by itself if it breaks.
+    pub async fn client(&self, node: &str) -> Option<CacheServiceClient<Channel>> {
+        if let Some(client) = self.clients.get(node) {
+            return Some(client.clone());
+        }
+        let client = self.connect(node).await?;
+        self.clients.insert(node.to_string(), client.clone());
+        Some(client)
+    }
+
+    fn replica_nodes(&self, key: &str) -> Vec<String> {
+        self.hasher
+            .get_n_nodes(key, self.replication_factor + 1) // +1 to include local node
+            .into_iter()
+            .filter(|node| *node != self.local_node_address)
+            .collect()
+    }
+
+    /// Ships every mutation produced by the cache to the replicas of its key.
+    pub async fn run(self: Arc<Self>, mut receiver: UnboundedReceiver<Mutation>) {
+        while let Some(mutation) = receiver.recv().await {
+            self.replicate(&mutation);
+        }
+    }
+
+    /// Queues a mutation for each replica of its key without waiting, so that
+    /// a slow or unreachable peer never holds up the others. A peer whose
+    /// queue is full misses the mutation, which read repair and anti-entropy
+    /// then bring over.
+    pub fn replicate(self: &Arc<Self>, mutation: &Mutation) {
+        for node in self.replica_nodes(mutation.key()) {
+            let outgoing = Outgoing {
+                mutation: mutation.clone(),
+                ack: None,
+            };
+            if let Err(mpsc::error::TrySendError::Full(_)) = self.queue(&node).try_send(outgoing) {
+                warn!(
+                    "Replication queue to node {} is full; leaving key {} to anti-entropy",
+                    node,
+                    mutation.key()
+                );
+            }
+        }
+    }
+
+    /// Sends a mutation to a single node. Returns whether the node acknowledged it.
+    pub async fn send(self: &Arc<Self>, node: &str, mutation: &Mutation) -> bool {
+        self.send_all(node, vec![mutation.clone()]).await[0]
+    }
+
+    /// Sends mutations to a single node over its replication stream and waits
+    /// for the acknowledgements. Returns, per mutation, whether it was confirmed.
+    pub async fn send_all(self: &Arc<Self>, node: &str, mutations: Vec<Mutation>) -> Vec<bool> {
+        let mut acks = Vec::with_capacity(mutations.len());
+        for mutation in mutations {
+            let (ack, confirmed) = oneshot::channel();
+            self.enqueue(node, mutation, Some(ack)).await;
+            acks.push(confirmed);
+        }
+        join_all(acks)
+            .await
+            .into_iter()
+            .map(|confirmed| confirmed.unwrap_or(false))
+            .collect()
+    }
+
+    /// Queues a mutation for a peer, waiting when the peer's queue is full so
+    /// that a slow peer slows down its own senders instead of growing memory.
+    async fn enqueue(
+        self: &Arc<Self>,
+        node: &str,
+        mutation: Mutation,
+        ack: Option<oneshot::Sender<bool>>,
+    ) {
+        let sender = self.queue(node);
+        if let Err(mpsc::error::SendError(outgoing)) = sender.send(Outgoing { mutation, ack }).await
+        {
+            if let Some(ack) = outgoing.ack {
+                let _ = ack.send(false);
+            }
+        }
+    }
+
+    /// Returns the queue of a peer's replication stream, opening the stream if
+    /// there is none.
+    fn queue(self: &Arc<Self>, node: &str) -> mpsc::Sender<Outgoing> {
+        self.peers
+            .entry(node.to_string())
+            .or_insert_with(|| {
+                let (sender, receiver) = mpsc::channel(PEER_QUEUE_SIZE);
+                tokio::spawn(self.clone().stream_to(node.to_string(), receiver));
+                sender
+            })
+            .clone()
+    }
+
+    /// Drains a peer's queue into a `ReplicateStream` call. A stream that cannot
+    /// be opened or breaks is reopened up to `REPLICATION_ATTEMPTS` times in a
+    /// row, resending whatever was not acknowledged; after that the queued
+    /// mutations are failed and left to read repair and anti-entropy.
+    async fn stream_to(self: Arc<Self>, node: String, mut queue: mpsc::Receiver<Outgoing>) {
+        let mut unacknowledged = Vec::new();
+        let mut attempt = 0;
+        while attempt < REPLICATION_ATTEMPTS {
+            if attempt > 0 {
+                warn!(
+                    "Reopening replication stream to node {} (attempt {} of {})",
+                    node,
+                    attempt + 1,
+                    REPLICATION_ATTEMPTS
+                );
+                sleep(RETRY_BACKOFF * attempt).await;
+            }
+            attempt += 1;
+            match self.stream(&node, &mut queue, &mut unacknowledged).await {
+                Stream::Drained => break,
+                Stream::Progressed => attempt = 0,
+                Stream::Failed => {}
+            }
+        }
+
+        // Forget the peer so the next mutation opens a fresh stream, and fail
+        // everything that was never acknowledged.
+        self.peers.remove(&node);
+        queue.close();
+        for outgoing in unacknowledged {
+            if let Some(ack) = outgoing.ack {
+                let _ = ack.send(false);
+            }
+        }
+        while let Ok(outgoing) = queue.try_recv() {
+            if let Some(ack) = outgoing.ack {
+                let _ = ack.send(false);
+            }
+        }
+    }
+
+    /// Runs one `ReplicateStream` call, batching whatever is queued and keeping
+    /// at most `MAX_IN_FLIGHT` unacknowledged batches. `unacknowledged` is sent
+    /// first and, when the stream ends, holds what the peer never confirmed.
+    async fn stream(
+        &self,
+        node: &str,
+        queue: &mut mpsc::Receiver<Outgoing>,
+        unacknowledged: &mut Vec<Outgoing>,
+    ) -> Stream {
+        let Some(mut client) = self.connect(node).await else {
+            return Stream::Failed;
+        };
+        let (batches, outbound) = mpsc::channel(MAX_IN_FLIGHT);
+        let mut acks = match client
+            .replicate_stream(self.peer_request(ReceiverStream::new(outbound)))
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                error!("Failed to open replication stream to node {}: {}", node, e);
+                return Stream::Failed;
+            }
+        };
+
+        let mut pending: BTreeMap<u64, Vec<Outgoing>> = BTreeMap::new();
+        let mut sequence = 0u64;
+        let mut result = Stream::Failed;
+        let mut resend = std::mem::take(unacknowledged);
+        while !resend.is_empty() {
+            let rest = resend.split_off(resend.len().min(BATCH_SIZE));
+            sequence += 1;
+            let entries = resend.iter().map(|o| o.mutation.to_request()).collect();
+            pending.insert(sequence, resend);
+            resend = rest;
+            if batches
+                .send(ReplicationBatch { sequence, entries })
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                ack = acks.message() => match ack {
+                    Ok(Some(ack)) => {
+                        result = Stream::Progressed;
+                        for outgoing in pending.remove(&ack.sequence).unwrap_or_default() {
+                            if let Some(ack) = outgoing.ack {
+                                let _ = ack.send(true);
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Replication stream to node {} failed: {}", node, e);
+                        break;
+                    }
+                },
+                outgoing = queue.recv(), if pending.len() < MAX_IN_FLIGHT => {
+                    let Some(outgoing) = outgoing else {
+                        result = Stream::Drained;
+                        break;
+                    };
+                    let mut batch = vec![outgoing];
+                    while batch.len() < BATCH_SIZE {
+                        match queue.try_recv() {
+                            Ok(outgoing) => batch.push(outgoing),
+                            Err(_) => break,
+                        }
+                    }
+                    sequence += 1;
+                    let entries = batch.iter().map(|o| o.mutation.to_request()).collect();
+                    pending.insert(sequence, batch);
+                    if batches.send(ReplicationBatch { sequence, entries }).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        unacknowledged.extend(pending.into_values().flatten());
+        unacknowledged.extend(resend);
+        result
+    }
+
+    /// Reads `key` from every replica, returns the newest state and writes it
+    /// back in the background to replicas that are missing it or hold an older
+    /// version. `local` is this node's own state for the key.
+    pub async fn read_repair(
+        self: &Arc<Self>,
+        key: &str,
+        local: Option<Mutation>,
+    ) -> Option<Mutation> {
+        let nodes = self.replica_nodes(key);
+        let reads = join_all(nodes.iter().map(|node| self.read_replica(node, key))).await;
+
+        let mut newest = local;
+        let mut states = Vec::new();
+        for (node, read) in nodes.into_iter().zip(reads) {
+            // Unreachable replicas are skipped, not repaired
+            let Some(state) = read else {
+                continue;
             };
-            if let Err(e) = client.put(tonic::Request::new(entry)).await {
-                error!("Failed to replicate to node {}: {}", addr, e);
+            let version = state.as_ref().map_or(0, Mutation::version);
+            if newest.as_ref().is_none_or(|n| version > n.version()) {
+                newest = state.clone();
+            }
+            states.push((node, state));
+        }
+
+        let newest = newest?;
+        let stale: Vec<(String, &'static str)> = states
+            .into_iter()
+            .filter_map(|(node, state)| match state {
+                None if matches!(newest, Mutation::Delete { .. }) => None,
+                None => Some((node, "missing")),
+                Some(state) if state.version() < newest.version() => Some((node, "stale")),
+                Some(_) => None,
+            })
+            .collect();
+
+        if !stale.is_empty() {
+            let replicator = self.clone();
+            let mutation = newest.clone();
+            tokio::spawn(async move {
+                for (node, reason) in stale {
+                    info!(
+                        "Repairing {} replica of key {} on node {}",
+                        reason,
+                        mutation.key(),
+                        node
+                    );
+                    if !replicator.send(&node, &mutation).await {
+                        warn!("Failed to repair key {} on node {}", mutation.key(), node);
+                        continue;
+                    }
+                    replicator
+                        .monitoring
+                        .read_repairs
+                        .with_label_values(&[reason])
+                        .inc();
+                }
+            });
+        }
+
+        Some(newest)
+    }
+
+    /// Returns `None` when the replica could not be reached, `Some(None)` when
+    /// it holds nothing for the key.
+    pub async fn read_replica(&self, node: &str, key: &str) -> Option<Option<Mutation>> {
+        let mut client = self.connect(node).await?;
+        let request = self.peer_request(CacheKey {
+            key: key.to_string(),
+            ..Default::default()
+        });
+        match client.read_replica(request).await {
+            Ok(response) => Some(Mutation::from_replica_state(response.into_inner())),
+            Err(e) => {
+                warn!("Failed to read key {} from node {}: {}", key, node, e);
+                None
             }
         }
     }
@@ -32,7 +32,7 @@ impl SearchIndex {
         doc.add_text(value_field, value);
 
         let mut writer = self.writer.lock().unwrap();
-        writer.add_document(doc);
+        let _ = writer.add_document(doc);
         writer.commit().unwrap();
     }
 
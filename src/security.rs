@@ -2,7 +2,8 @@
 
 use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
-use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
 use tonic::Request;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,31 +14,117 @@ struct Claims {
 
 pub struct Security {
     pub tls_config: Option<ServerTlsConfig>,
+    /// Used to connect to peers. Presents this node's certificate, so peers
+    /// that set a CA authenticate each other (mTLS).
+    pub client_tls_config: Option<ClientTlsConfig>,
     pub jwt_secret: Option<String>,
+    pub admin_token: Option<String>,
+    pub peer_token: Option<String>,
+}
+
+/// Whether `presented` is the configured admin token. Admin actions are
+/// refused when no admin token is configured.
+pub fn is_admin_token(admin_token: Option<&str>, presented: Option<&str>) -> bool {
+    matches!((admin_token, presented), (Some(expected), Some(presented)) if expected == presented)
+}
+
+/// Wraps a request forwarded to another node, presenting `peer_token` in
+/// `x-peer-token` so that the node trusts the request's `forwarded` flag.
+pub fn peer_request<T>(peer_token: Option<&str>, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    if let Some(token) = peer_token.and_then(|token| MetadataValue::try_from(token).ok()) {
+        request.metadata_mut().insert("x-peer-token", token);
+    }
+    request
 }
 
 impl Security {
     pub fn new(config: &crate::config::Config) -> Self {
-        let tls_config = if let (Some(cert_path), Some(key_path)) =
+        let (tls_config, client_tls_config) = if let (Some(cert_path), Some(key_path)) =
             (&config.tls_cert_path, &config.tls_key_path)
         {
             let cert = std::fs::read(cert_path).expect("Failed to read TLS certificate");
             let key = std::fs::read(key_path).expect("Failed to read TLS key");
             let identity = Identity::from_pem(cert, key);
-            Some(ServerTlsConfig::new().identity(identity))
+            let mut server = ServerTlsConfig::new().identity(identity.clone());
+            let mut client = ClientTlsConfig::new().identity(identity);
+            if let Some(ca_path) = &config.tls_ca_path {
+                let ca = std::fs::read(ca_path).expect("Failed to read TLS CA certificate");
+                let ca = Certificate::from_pem(ca);
+                server = server.client_ca_root(ca.clone());
+                client = client.ca_certificate(ca);
+            }
+            if let Some(server_name) = &config.tls_server_name {
+                client = client.domain_name(server_name.clone());
+            }
+            (Some(server), Some(client))
         } else {
-            None
+            (None, None)
         };
 
         let jwt_secret = config.jwt_secret.clone();
 
         Self {
             tls_config,
+            client_tls_config,
             jwt_secret,
+            admin_token: config.admin_token.clone(),
+            peer_token: config.peer_token.clone(),
+        }
+    }
+
+    /// Checks that the request carries the admin token in `x-admin-token`.
+    #[allow(clippy::result_large_err)]
+    pub fn authorize_admin<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        let presented = request
+            .metadata()
+            .get("x-admin-token")
+            .and_then(|t| t.to_str().ok());
+        if is_admin_token(self.admin_token.as_deref(), presented) {
+            Ok(())
+        } else {
+            Err(tonic::Status::permission_denied("Admin token required"))
         }
     }
 
-    pub fn authenticate<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+    /// Whether the request was forwarded by another node: it carries the peer
+    /// token in `x-peer-token`. No request does when no peer token is
+    /// configured.
+    pub fn is_peer<T>(&self, request: &Request<T>) -> bool {
+        let presented = request
+            .metadata()
+            .get("x-peer-token")
+            .and_then(|t| t.to_str().ok());
+        matches!(
+            (self.peer_token.as_deref(), presented),
+            (Some(expected), Some(presented)) if expected == presented
+        )
+    }
+
+    /// Checks a request made to an RPC that only nodes call: it must carry
+    /// the peer token, which then stands in for a client token. Without a
+    /// peer token configured, the request is authenticated like a client's.
+    #[allow(clippy::result_large_err)]
+    pub fn authenticate_peer<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        if self.peer_token.is_none() {
+            return self.authenticate(request).map(|_| ());
+        }
+        if self.is_peer(request) {
+            Ok(())
+        } else {
+            Err(tonic::Status::permission_denied("Peer token required"))
+        }
+    }
+
+    /// Wraps a request forwarded to another node so that it trusts it.
+    pub fn peer_request<T>(&self, message: T) -> Request<T> {
+        peer_request(self.peer_token.as_deref(), message)
+    }
+
+    /// Checks the request's token, if tokens are required, and returns who
+    /// the client is: the token subject, or else the peer's IP address.
+    #[allow(clippy::result_large_err)]
+    pub fn authenticate<T>(&self, request: &Request<T>) -> Result<String, tonic::Status> {
         if let Some(secret) = &self.jwt_secret {
             let token = request
                 .metadata()
@@ -49,9 +136,12 @@ impl Security {
             let decoding_key = DecodingKey::from_secret(secret.as_bytes());
             let validation = Validation::default();
 
-            decode::<Claims>(token, &decoding_key, &validation)
+            let claims = decode::<Claims>(token, &decoding_key, &validation)
                 .map_err(|_| tonic::Status::unauthenticated("Invalid token"))?;
+            return Ok(claims.claims.sub);
         }
-        Ok(())
+        Ok(request
+            .remote_addr()
+            .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()))
     }
 }
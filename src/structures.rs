@@ -0,0 +1,294 @@
+// src/structures.rs
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tonic::Status;
+
+/// A typed value stored under a single key. Updates are made on the key's
+/// owner, which replicates each as a `Delta`; a replica applies it only if it
+/// holds the version the delta was made against. A new structure, and the
+/// state anti-entropy and read repair ship, travel whole and replace the
+/// replica's copy like a put.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Structure {
+    Hash(BTreeMap<String, Bytes>),
+    List(VecDeque<Bytes>),
+    Set(BTreeSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+impl Structure {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Structure::Hash(hash) => hash.is_empty(),
+            Structure::List(list) => list.is_empty(),
+            Structure::Set(set) => set.is_empty(),
+            Structure::SortedSet(sorted) => sorted.scores.is_empty(),
+        }
+    }
+
+    /// Approximate memory used by the contents, for memory accounting.
+    pub fn size(&self) -> usize {
+        match self {
+            Structure::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len())
+                .sum(),
+            Structure::List(list) => list.iter().map(Bytes::len).sum(),
+            Structure::Set(set) => set.iter().map(Bytes::len).sum(),
+            Structure::SortedSet(sorted) => sorted
+                .scores
+                .keys()
+                .map(|member| member.len() + std::mem::size_of::<f64>())
+                .sum(),
+        }
+    }
+
+    /// Renders the structure as JSON for a plain `Get`, with binary values
+    /// shown as lossy UTF-8.
+    pub fn render(&self) -> Bytes {
+        let text = |value: &Bytes| String::from_utf8_lossy(value).into_owned();
+        let rendered = match self {
+            Structure::Hash(hash) => Value::Object(
+                hash.iter()
+                    .map(|(field, value)| (field.clone(), Value::String(text(value))))
+                    .collect(),
+            ),
+            Structure::List(list) => json!(list.iter().map(text).collect::<Vec<_>>()),
+            Structure::Set(set) => json!(set.iter().map(text).collect::<Vec<_>>()),
+            Structure::SortedSet(sorted) => json!(sorted
+                .order
+                .iter()
+                .map(|(score, member)| json!({ "member": text(member), "score": score.0 }))
+                .collect::<Vec<_>>()),
+        };
+        Bytes::from(rendered.to_string())
+    }
+}
+
+/// One update to a structure, as replicated to the key's replicas.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Delta {
+    HashSet { fields: Vec<(String, Bytes)> },
+    HashDelete { fields: Vec<String> },
+    ListPush { values: Vec<Bytes>, front: bool },
+    ListPop { count: usize, front: bool },
+    SetAdd { members: Vec<Bytes> },
+    SetRemove { members: Vec<Bytes> },
+    SortedSetAdd { members: Vec<(Bytes, f64)> },
+}
+
+impl Delta {
+    /// Applies the update to `structure`. Returns `false`, leaving it as it
+    /// was, if the structure is of another type.
+    pub fn apply(&self, structure: &mut Structure) -> bool {
+        match (self, structure) {
+            (Delta::HashSet { fields }, Structure::Hash(hash)) => {
+                hash.extend(fields.iter().cloned());
+            }
+            (Delta::HashDelete { fields }, Structure::Hash(hash)) => {
+                for field in fields {
+                    hash.remove(field);
+                }
+            }
+            (Delta::ListPush { values, front }, Structure::List(list)) => {
+                for value in values {
+                    if *front {
+                        list.push_front(value.clone());
+                    } else {
+                        list.push_back(value.clone());
+                    }
+                }
+            }
+            (Delta::ListPop { count, front }, Structure::List(list)) => {
+                let count = (*count).min(list.len());
+                if *front {
+                    list.drain(..count);
+                } else {
+                    list.truncate(list.len() - count);
+                }
+            }
+            (Delta::SetAdd { members }, Structure::Set(set)) => {
+                set.extend(members.iter().cloned());
+            }
+            (Delta::SetRemove { members }, Structure::Set(set)) => {
+                for member in members {
+                    set.remove(member);
+                }
+            }
+            (Delta::SortedSetAdd { members }, Structure::SortedSet(sorted)) => {
+                for (member, score) in members {
+                    sorted.insert(member.clone(), *score);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Implemented by the contents of each `Structure` variant, so that cache
+/// operations can work on the one they expect.
+pub trait Contents: Default + Sized {
+    fn wrap(self) -> Structure;
+    fn unwrap(structure: Structure) -> Option<Self>;
+    fn peek(structure: &Structure) -> Option<&Self>;
+}
+
+macro_rules! contents {
+    ($variant:ident, $type:ty) => {
+        impl Contents for $type {
+            fn wrap(self) -> Structure {
+                Structure::$variant(self)
+            }
+
+            fn unwrap(structure: Structure) -> Option<Self> {
+                match structure {
+                    Structure::$variant(contents) => Some(contents),
+                    _ => None,
+                }
+            }
+
+            fn peek(structure: &Structure) -> Option<&Self> {
+                match structure {
+                    Structure::$variant(contents) => Some(contents),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+contents!(Hash, BTreeMap<String, Bytes>);
+contents!(List, VecDeque<Bytes>);
+contents!(Set, BTreeSet<Bytes>);
+contents!(SortedSet, SortedSet);
+
+/// A score ordered with `f64::total_cmp`, so that it can key a `BTreeSet`.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then by member. Serialized as a list of
+/// `(score, member)` pairs.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(f64, Bytes)>", into = "Vec<(f64, Bytes)>")]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    order: BTreeSet<(Score, Bytes)>,
+}
+
+impl SortedSet {
+    /// Adds a member or updates its score. Returns whether it is new.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.order.remove(&(Score(previous), member.clone()));
+        }
+        self.order.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    /// Members with a score in `min..=max`, lowest first, at most `limit`.
+    pub fn range_by_score(&self, min: f64, max: f64, limit: usize) -> Vec<(Bytes, f64)> {
+        self.order
+            .range((Score(min), Bytes::new())..)
+            .take_while(|(score, _)| score.0 <= max)
+            .take(limit)
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// The member's zero-based position by ascending score, and its score.
+    pub fn rank(&self, member: &[u8]) -> Option<(usize, f64)> {
+        let score = self.score(member)?;
+        let rank = self
+            .order
+            .range(..(Score(score), Bytes::copy_from_slice(member)))
+            .count();
+        Some((rank, score))
+    }
+}
+
+impl From<Vec<(f64, Bytes)>> for SortedSet {
+    fn from(members: Vec<(f64, Bytes)>) -> Self {
+        let mut sorted = SortedSet::default();
+        for (score, member) in members {
+            sorted.insert(member, score);
+        }
+        sorted
+    }
+}
+
+impl From<SortedSet> for Vec<(f64, Bytes)> {
+    fn from(sorted: SortedSet) -> Self {
+        sorted
+            .order
+            .into_iter()
+            .map(|(score, member)| (score.0, member))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum StructureError {
+    /// The key holds a plain value or a structure of another type.
+    WrongType(String),
+    /// A sorted set score was NaN.
+    InvalidScore,
+    /// The key is locked by a prepared distributed transaction.
+    Locked(String),
+}
+
+impl std::fmt::Display for StructureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StructureError::WrongType(key) => {
+                write!(f, "Key {} holds a value of another type", key)
+            }
+            StructureError::InvalidScore => write!(f, "Scores must be numbers"),
+            StructureError::Locked(key) => {
+                write!(f, "Key {} is locked by a prepared transaction", key)
+            }
+        }
+    }
+}
+
+impl From<StructureError> for Status {
+    fn from(error: StructureError) -> Self {
+        match error {
+            StructureError::WrongType(_) => Status::failed_precondition(error.to_string()),
+            StructureError::InvalidScore => Status::invalid_argument(error.to_string()),
+            StructureError::Locked(_) => Status::aborted(error.to_string()),
+        }
+    }
+}
@@ -1,13 +1,21 @@
 //transaction_manager.rs
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
 use uuid::Uuid;
 
-#[derive(Debug)]
+use crate::cache::Typed;
+use crate::monitoring::Monitoring;
+use crate::proto::TransactionInfo;
+
+/// A write buffered in a transaction until it commits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Operation {
     Put {
         key: String,
@@ -16,90 +24,510 @@ pub enum Operation {
     },
     Evict {
         key: String,
-        value: Bytes,
-        ttl: Option<Duration>,
-    }, // Include `value` and `ttl`
+    },
+}
+
+impl Operation {
+    pub fn key(&self) -> &str {
+        match self {
+            Operation::Put { key, .. } | Operation::Evict { key } => key,
+        }
+    }
+}
+
+/// How a transaction's reads and commit relate to concurrent writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IsolationLevel {
+    /// Reads see the latest committed value; commits never conflict and the
+    /// last writer wins.
+    ReadCommitted,
+    /// Reads see the cache as of the transaction's start: a read of a key
+    /// written since fails with `Conflict` rather than return the newer
+    /// value, and the first value read of a key is kept for the rest of the
+    /// transaction. A commit fails if any key it writes was written by
+    /// someone else after the transaction began (first-committer-wins).
+    ///
+    /// The snapshot and the commit are atomic on this node only. Replicas
+    /// receive a commit's writes one at a time, so readers on a replica can
+    /// see part of a commit, and a write from another node whose clock lags
+    /// can order before a snapshot that began after it.
+    Snapshot,
+}
+
+impl FromStr for IsolationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read_committed" | "read-committed" => Ok(IsolationLevel::ReadCommitted),
+            "snapshot" | "repeatable_read" => Ok(IsolationLevel::Snapshot),
+            other => Err(format!("Unknown isolation level: {}", other)),
+        }
+    }
+}
+
+/// When a transaction's writes reach the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionMode {
+    /// Writes are buffered and applied together on commit.
+    Buffered,
+    /// Writes are applied as they are made, after saving a before-image of
+    /// the key; rollback and aborted commits restore the before-images.
+    ///
+    /// Other clients, and replicas, see the writes before the transaction
+    /// commits, and may act on writes that are rolled back later: this mode
+    /// allows dirty reads whatever the isolation level, which only governs
+    /// the transaction's own reads and commit checks. Commits are checked on
+    /// the node that ran the transaction alone, without two-phase commit.
+    Applied,
+}
+
+impl FromStr for TransactionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "buffered" => Ok(TransactionMode::Buffered),
+            "applied" => Ok(TransactionMode::Applied),
+            other => Err(format!("Unknown transaction mode: {}", other)),
+        }
+    }
+}
+
+/// The state of a key before an applied-mode transaction first wrote it.
+pub struct BeforeImage {
+    /// The stored (compressed) value and when it expires, or `None` if the
+    /// key did not exist.
+    pub value: Option<(Bytes, Option<Instant>)>,
+    /// The counter or structure the key held besides its value, if any.
+    pub typed: Option<Typed>,
+    /// Version of the key before the write, including a tombstone.
+    pub version: u64,
+    /// Version the transaction expected the key to be at, from a read or
+    /// `WATCH` before the write.
+    pub expected: u64,
+    /// Version of the transaction's latest write of the key.
+    pub written: u64,
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    Disabled,
+    NotFound(String),
+    /// The transaction outlived the transaction timeout and was discarded.
+    Expired(String),
+    /// The client already has the maximum number of open transactions.
+    LimitExceeded(String),
+    /// A distributed commit could not reach agreement, for the given reason.
+    Unavailable(String),
+    /// `key` was written by someone else after the transaction began, or
+    /// after the transaction read or watched it.
+    Conflict(String),
+    /// `key` is locked by a prepared distributed transaction.
+    Locked(String),
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::Disabled => write!(f, "Transactions are disabled"),
+            TransactionError::NotFound(id) => write!(f, "Transaction {} not found", id),
+            TransactionError::Expired(id) => write!(f, "Transaction {} has expired", id),
+            TransactionError::LimitExceeded(client) => {
+                write!(f, "Client {} has too many open transactions", client)
+            }
+            TransactionError::Unavailable(reason) => {
+                write!(f, "Transaction aborted: {}", reason)
+            }
+            TransactionError::Conflict(key) => write!(
+                f,
+                "Transaction aborted: key {} was modified concurrently",
+                key
+            ),
+            TransactionError::Locked(key) => {
+                write!(f, "Key {} is locked by a prepared transaction", key)
+            }
+        }
+    }
+}
+
+impl From<TransactionError> for Status {
+    fn from(error: TransactionError) -> Self {
+        match error {
+            TransactionError::Disabled => Status::unimplemented(error.to_string()),
+            TransactionError::NotFound(_) => Status::not_found(error.to_string()),
+            TransactionError::Expired(_) => Status::deadline_exceeded(error.to_string()),
+            TransactionError::LimitExceeded(_) => Status::resource_exhausted(error.to_string()),
+            TransactionError::Unavailable(_) => Status::unavailable(error.to_string()),
+            TransactionError::Conflict(_) | TransactionError::Locked(_) => {
+                Status::aborted(error.to_string())
+            }
+        }
+    }
 }
 
 pub struct Transaction {
     pub id: String,
+    /// The client that began the transaction, for per-client limits.
+    pub client: String,
     pub operations: Vec<Operation>,
+    pub created_at: Instant,
     pub expires_at: Instant,
+    pub isolation: IsolationLevel,
+    /// Cache clock when the transaction began. Under snapshot isolation, a
+    /// written key with a newer version is a conflict.
+    pub start_version: u64,
+    /// Values pinned by the first read of each key, under snapshot isolation;
+    /// `None` for a key that did not exist.
+    reads: HashMap<String, Option<(Bytes, u64)>>,
+    /// Version of every key the transaction read or watched when it first
+    /// did. The commit aborts if any of them has changed since.
+    pub watched: HashMap<String, u64>,
+    /// Before-images of the keys written in applied mode.
+    pub before_images: HashMap<String, BeforeImage>,
+}
+
+impl Transaction {
+    fn info(&self) -> TransactionInfo {
+        let now = Instant::now();
+        let mut written_keys: Vec<String> = self
+            .operations
+            .iter()
+            .map(|operation| operation.key().to_string())
+            .collect();
+        written_keys.sort();
+        written_keys.dedup();
+        let mut watched_keys: Vec<String> = self.watched.keys().cloned().collect();
+        watched_keys.sort();
+        TransactionInfo {
+            transaction_id: self.id.clone(),
+            client: self.client.clone(),
+            isolation: format!("{:?}", self.isolation),
+            age_ms: now.duration_since(self.created_at).as_millis() as u64,
+            expires_in_ms: self.expires_at.saturating_duration_since(now).as_millis() as u64,
+            expired: self.expires_at <= now,
+            operations: self.operations.len() as u32,
+            written_keys,
+            watched_keys,
+        }
+    }
 }
 
 pub struct TransactionManager {
     transactions: Mutex<HashMap<String, Transaction>>,
+    /// Transactions discarded on expiry and when, so that late commits get
+    /// `Expired` rather than `NotFound`. Forgotten after another timeout.
+    expired: Mutex<HashMap<String, Instant>>,
     timeout: Duration,
+    /// Most open transactions per client; 0 for no limit.
+    max_per_client: usize,
     enabled: bool,
+    monitoring: Arc<Monitoring>,
 }
 
 impl TransactionManager {
-    pub fn new(timeout: Duration) -> Self {
+    pub fn new(timeout: Duration, max_per_client: usize, monitoring: Arc<Monitoring>) -> Self {
         Self {
             transactions: Mutex::new(HashMap::new()),
+            expired: Mutex::new(HashMap::new()),
             timeout,
+            max_per_client,
             enabled: true,
+            monitoring,
         }
     }
 
-    pub fn disabled() -> Self {
+    pub fn disabled(monitoring: Arc<Monitoring>) -> Self {
         Self {
             transactions: Mutex::new(HashMap::new()),
+            expired: Mutex::new(HashMap::new()),
             timeout: Duration::from_secs(0),
+            max_per_client: 0,
             enabled: false,
+            monitoring,
         }
     }
 
-    pub fn begin_transaction(&self) -> String {
+    pub fn begin_transaction(
+        &self,
+        client: &str,
+        isolation: IsolationLevel,
+        start_version: u64,
+    ) -> Result<String, TransactionError> {
         if !self.enabled {
-            return "".to_string();
+            return Err(TransactionError::Disabled);
+        }
+        let mut transactions = self.transactions.lock().unwrap();
+        if self.max_per_client > 0
+            && transactions
+                .values()
+                .filter(|transaction| transaction.client == client)
+                .count()
+                >= self.max_per_client
+        {
+            return Err(TransactionError::LimitExceeded(client.to_string()));
         }
         let id = Uuid::new_v4().to_string();
         let transaction = Transaction {
             id: id.clone(),
+            client: client.to_string(),
             operations: Vec::new(),
+            created_at: Instant::now(),
             expires_at: Instant::now() + self.timeout,
+            isolation,
+            start_version,
+            reads: HashMap::new(),
+            watched: HashMap::new(),
+            before_images: HashMap::new(),
         };
-        self.transactions
-            .lock()
-            .unwrap()
-            .insert(id.clone(), transaction);
-        id
+        transactions.insert(id.clone(), transaction);
+        self.monitoring
+            .active_transactions
+            .set(transactions.len() as i64);
+        Ok(id)
     }
 
-    pub fn add_operation(&self, transaction_id: &str, operation: Operation) {
+    /// Looks up an open transaction. One that has outlived the timeout is
+    /// rejected even if the sweeper has not discarded it yet, which is left
+    /// to the sweeper so that applied writes are undone in one place.
+    fn open<'a>(
+        &self,
+        transactions: &'a mut HashMap<String, Transaction>,
+        transaction_id: &str,
+    ) -> Result<&'a mut Transaction, TransactionError> {
         if !self.enabled {
-            return;
+            return Err(TransactionError::Disabled);
+        }
+        match transactions.get_mut(transaction_id) {
+            Some(transaction) if transaction.expires_at <= Instant::now() => {
+                Err(TransactionError::Expired(transaction_id.to_string()))
+            }
+            Some(transaction) => Ok(transaction),
+            None if self.expired.lock().unwrap().contains_key(transaction_id) => {
+                Err(TransactionError::Expired(transaction_id.to_string()))
+            }
+            None => Err(TransactionError::NotFound(transaction_id.to_string())),
+        }
+    }
+
+    /// Removes an open transaction so that it can be applied or discarded.
+    fn take(&self, transaction_id: &str) -> Result<Transaction, TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        self.open(&mut transactions, transaction_id)?;
+        let transaction = transactions.remove(transaction_id).unwrap();
+        self.monitoring
+            .active_transactions
+            .set(transactions.len() as i64);
+        Ok(transaction)
+    }
+
+    /// Buffers a write in the transaction. Nothing reaches the cache until commit.
+    pub fn add_operation(
+        &self,
+        transaction_id: &str,
+        operation: Operation,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        self.open(&mut transactions, transaction_id)?
+            .operations
+            .push(operation);
+        Ok(())
+    }
+
+    /// Records a write that is applied to the cache right away, with the
+    /// key's state before it: `previous` is the stored value and expiry,
+    /// `typed` the counter or structure stored with it and `version` the key's
+    /// version. Only the first write of a key keeps its
+    /// before-image; `written` is the version of this write.
+    pub fn add_applied(
+        &self,
+        transaction_id: &str,
+        operation: Operation,
+        previous: Option<(Bytes, Option<Instant>)>,
+        typed: Option<Typed>,
+        version: u64,
+        written: u64,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = self.open(&mut transactions, transaction_id)?;
+        let key = operation.key().to_string();
+        transaction.operations.push(operation);
+        let expected = transaction.watched.remove(&key).unwrap_or(version);
+        transaction
+            .before_images
+            .entry(key)
+            .or_insert(BeforeImage {
+                value: previous,
+                typed,
+                version,
+                expected,
+                written,
+            })
+            .written = written;
+        Ok(())
+    }
+
+    /// Returns what the transaction already knows about `key`, so that reads
+    /// inside a transaction see its own writes: `Some(Some(_))` for a put or a
+    /// pinned read, `Some(None)` for an evict or a pinned miss and `None` if
+    /// the key has to be read from the cache. Uncommitted writes have version 0.
+    pub fn read(
+        &self,
+        transaction_id: &str,
+        key: &str,
+    ) -> Result<Option<Option<(Bytes, u64)>>, TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = self.open(&mut transactions, transaction_id)?;
+        Ok(transaction
+            .operations
+            .iter()
+            .rev()
+            .find(|operation| operation.key() == key)
+            .map(|operation| match operation {
+                Operation::Put { value, .. } => Some((value.clone(), 0)),
+                Operation::Evict { .. } => None,
+            })
+            .or_else(|| transaction.reads.get(key).cloned()))
+    }
+
+    /// Records what the transaction read, from this node or a peer: `value`
+    /// is `None` for a miss, with the version of the key's tombstone or 0.
+    /// The key is watched at that version and, under snapshot isolation,
+    /// later reads of the key return the same result even if it changes in
+    /// the cache. Under snapshot isolation a key written after the
+    /// transaction began fails the read with `Conflict`, since the value it
+    /// had at the start is no longer kept.
+    pub fn record_read(
+        &self,
+        transaction_id: &str,
+        key: &str,
+        value: Option<Bytes>,
+        version: u64,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = self.open(&mut transactions, transaction_id)?;
+        if transaction.isolation == IsolationLevel::Snapshot {
+            if version > transaction.start_version {
+                return Err(TransactionError::Conflict(key.to_string()));
+            }
+            transaction
+                .reads
+                .entry(key.to_string())
+                .or_insert(value.map(|value| (value, version)));
         }
-        if let Some(transaction) = self.transactions.lock().unwrap().get_mut(transaction_id) {
-            transaction.operations.push(operation);
+        transaction
+            .watched
+            .entry(key.to_string())
+            .or_insert(version);
+        Ok(())
+    }
+
+    /// Watches `key` at `version`, like Redis `WATCH`. A key keeps the version
+    /// it was first watched or read at; a key with a before-image is already
+    /// checked through it.
+    pub fn watch(
+        &self,
+        transaction_id: &str,
+        key: &str,
+        version: u64,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = self.open(&mut transactions, transaction_id)?;
+        if !transaction.before_images.contains_key(key) {
+            transaction
+                .watched
+                .entry(key.to_string())
+                .or_insert(version);
         }
+        Ok(())
     }
 
-    pub fn commit(&self, transaction_id: &str) -> Option<Vec<Operation>> {
+    /// Removes the transaction so that it can be applied. The caller reports
+    /// how the commit ended through `record_outcome`.
+    pub fn commit(&self, transaction_id: &str) -> Result<Transaction, TransactionError> {
+        self.take(transaction_id)
+    }
+
+    /// Removes the transaction so that its before-images can be restored.
+    pub fn rollback(&self, transaction_id: &str) -> Result<Transaction, TransactionError> {
+        let transaction = self.take(transaction_id)?;
+        self.record_outcome("rolled_back");
+        Ok(transaction)
+    }
+
+    /// Removes a transaction on an administrator's request, expired or not,
+    /// so that its before-images can be restored.
+    pub fn abort(&self, transaction_id: &str) -> Result<Transaction, TransactionError> {
         if !self.enabled {
-            return None;
+            return Err(TransactionError::Disabled);
         }
-        self.transactions
-            .lock()
-            .unwrap()
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = transactions
             .remove(transaction_id)
-            .map(|t| t.operations)
+            .ok_or_else(|| TransactionError::NotFound(transaction_id.to_string()))?;
+        self.monitoring
+            .active_transactions
+            .set(transactions.len() as i64);
+        self.record_outcome("aborted");
+        Ok(transaction)
     }
 
-    pub fn rollback(&self, transaction_id: &str) -> Option<Vec<Operation>> {
-        self.transactions
-            .lock()
-            .unwrap()
-            .remove(transaction_id)
-            .map(|transaction| transaction.operations) // Extract operations from the transaction
+    /// Describes an open transaction, including one that has expired but
+    /// was not swept yet.
+    pub fn describe(&self, transaction_id: &str) -> Result<TransactionInfo, TransactionError> {
+        if !self.enabled {
+            return Err(TransactionError::Disabled);
+        }
+        if let Some(transaction) = self.transactions.lock().unwrap().get(transaction_id) {
+            return Ok(transaction.info());
+        }
+        if self.expired.lock().unwrap().contains_key(transaction_id) {
+            return Err(TransactionError::Expired(transaction_id.to_string()));
+        }
+        Err(TransactionError::NotFound(transaction_id.to_string()))
+    }
+
+    /// Describes every open transaction, oldest first.
+    pub fn list(&self) -> Vec<TransactionInfo> {
+        let transactions = self.transactions.lock().unwrap();
+        let mut open: Vec<&Transaction> = transactions.values().collect();
+        open.sort_by_key(|transaction| transaction.created_at);
+        open.into_iter().map(Transaction::info).collect()
+    }
+
+    /// Counts a finished transaction: `committed`, `aborted`, `rolled_back`
+    /// or `expired`.
+    pub fn record_outcome(&self, outcome: &str) {
+        self.monitoring
+            .transactions
+            .with_label_values(&[outcome])
+            .inc();
     }
 
-    pub fn cleanup_expired(&self) {
+    /// Removes and returns every transaction that has outlived the timeout.
+    pub fn cleanup_expired(&self) -> Vec<Transaction> {
         let now = Instant::now();
-        self.transactions
-            .lock()
-            .unwrap()
-            .retain(|_, t| t.expires_at > now);
+        let mut transactions = self.transactions.lock().unwrap();
+        let ids: Vec<String> = transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.expires_at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut expired = self.expired.lock().unwrap();
+        expired.retain(|_, expired_at| now.duration_since(*expired_at) < self.timeout);
+        let removed: Vec<Transaction> = ids
+            .into_iter()
+            .filter_map(|id| {
+                expired.insert(id.clone(), now);
+                self.record_outcome("expired");
+                transactions.remove(&id)
+            })
+            .collect();
+        self.monitoring
+            .active_transactions
+            .set(transactions.len() as i64);
+        removed
     }
 }
@@ -0,0 +1,929 @@
+// src/two_phase_commit.rs
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::cache::Cache;
+use crate::hashing::ConsistentHashing;
+use crate::proto::{
+    DecideRequest, DecisionQuery, DecisionResponse, PrepareRequest, PrepareResponse,
+    TransactionOperation,
+};
+use crate::replication::Replicator;
+use crate::transaction_manager::{IsolationLevel, Operation, Transaction, TransactionError};
+
+/// Records appended to the decision log before it is compacted.
+const COMPACT_AFTER: usize = 1024;
+
+/// A line of the decision log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum LogRecord {
+    /// Participant: the writes are validated and their keys locked.
+    Prepared {
+        transaction_id: String,
+        coordinator: String,
+        operations: Vec<Operation>,
+    },
+    /// Participant: the prepared writes were applied or dropped.
+    Resolved { transaction_id: String },
+    /// Coordinator: the transaction commits. Logged before any participant
+    /// is told; aborts are not logged (presumed abort).
+    Committed {
+        transaction_id: String,
+        participants: Vec<String>,
+    },
+    /// Coordinator: every participant has applied the commit.
+    Done { transaction_id: String },
+}
+
+/// Append-only JSON-lines log, synced after every record.
+struct DecisionLog {
+    path: String,
+    file: Mutex<File>,
+    /// Records appended since the log was last rewritten.
+    appended: AtomicUsize,
+}
+
+impl DecisionLog {
+    fn open(path: &str) -> std::io::Result<(Self, Vec<LogRecord>)> {
+        let records = match File::open(path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str(&line).ok())
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok((
+            Self {
+                path: path.to_string(),
+                file: Mutex::new(file),
+                appended: AtomicUsize::new(0),
+            },
+            records,
+        ))
+    }
+
+    fn append(&self, record: &LogRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.appended.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replaces the log with `records`, dropping everything already resolved.
+    fn rewrite(&self, records: &[LogRecord]) -> std::io::Result<()> {
+        let temporary = format!("{}.tmp", self.path);
+        let mut file = File::create(&temporary)?;
+        for record in records {
+            serde_json::to_writer(&mut file, record)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+        *self.file.lock().unwrap() = OpenOptions::new().append(true).open(&self.path)?;
+        self.appended.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// A transaction this node prepared as a participant and that waits for
+/// the coordinator's decision.
+struct Prepared {
+    coordinator: String,
+    operations: Vec<Operation>,
+    since: Instant,
+}
+
+/// Commits transactions whose keys are owned by several nodes. The node
+/// that received the commit coordinates: it sends every owner its share of
+/// the writes (prepare), and each owner validates them and locks their keys.
+/// If all of them agree, the commit is logged and every owner applies its
+/// writes; otherwise they drop them. Participants and coordinator log every
+/// step before acknowledging it, so after a crash participants finish
+/// in-doubt transactions by asking the coordinator, and the coordinator
+/// re-sends commits that not every participant acknowledged. A coordinator
+/// without a record of a transaction it is not preparing presumes it aborted.
+///
+/// Every change to `prepared` or `committed` is logged while the map is
+/// locked, so that compacting the log under both locks loses nothing.
+pub struct TwoPhaseCommit {
+    cache: Arc<Cache>,
+    hasher: Arc<ConsistentHashing>,
+    replicator: Arc<Replicator>,
+    log: DecisionLog,
+    prepared: Mutex<HashMap<String, Prepared>>,
+    /// Committed transactions, with the participants that have not
+    /// acknowledged the commit yet.
+    committed: Mutex<HashMap<String, Vec<String>>>,
+    preparing: Mutex<HashSet<String>>,
+    interval: Duration,
+    /// How long a transaction stays in doubt once its coordinator has left
+    /// the cluster; zero for as long as it takes.
+    in_doubt_timeout: Duration,
+}
+
+impl TwoPhaseCommit {
+    /// Opens the decision log and takes back the locks of transactions that
+    /// were in doubt when the node stopped.
+    pub fn new(
+        cache: Arc<Cache>,
+        hasher: Arc<ConsistentHashing>,
+        replicator: Arc<Replicator>,
+        config: &crate::config::Config,
+    ) -> std::io::Result<Self> {
+        let (log, records) = DecisionLog::open(&config.transaction_log_path)?;
+        let mut prepared = HashMap::new();
+        let mut committed = HashMap::new();
+        for record in records {
+            match record {
+                LogRecord::Prepared {
+                    transaction_id,
+                    coordinator,
+                    operations,
+                } => {
+                    prepared.insert(
+                        transaction_id,
+                        Prepared {
+                            coordinator,
+                            operations,
+                            since: Instant::now(),
+                        },
+                    );
+                }
+                LogRecord::Resolved { transaction_id } => {
+                    prepared.remove(&transaction_id);
+                }
+                LogRecord::Committed {
+                    transaction_id,
+                    participants,
+                } => {
+                    committed.insert(transaction_id, participants);
+                }
+                LogRecord::Done { transaction_id } => {
+                    committed.remove(&transaction_id);
+                }
+            }
+        }
+
+        for (transaction_id, transaction) in &prepared {
+            cache.lock_keys(transaction_id, &transaction.operations);
+        }
+        if !prepared.is_empty() || !committed.is_empty() {
+            info!(
+                "Recovering {} prepared and {} committed transactions from {}",
+                prepared.len(),
+                committed.len(),
+                config.transaction_log_path
+            );
+        }
+
+        let two_phase = Self {
+            cache,
+            hasher,
+            replicator,
+            log,
+            prepared: Mutex::new(prepared),
+            committed: Mutex::new(committed),
+            preparing: Mutex::new(HashSet::new()),
+            interval: Duration::from_secs(config.transaction_recovery_interval.max(1)),
+            in_doubt_timeout: Duration::from_secs(config.in_doubt_timeout),
+        };
+        two_phase.compact()?;
+        Ok(two_phase)
+    }
+
+    /// Rewrites the log with only the transactions still prepared or
+    /// committed but unacknowledged.
+    fn compact(&self) -> std::io::Result<()> {
+        let prepared = self.prepared.lock().unwrap();
+        let committed = self.committed.lock().unwrap();
+        let mut pending = Vec::new();
+        for (transaction_id, transaction) in prepared.iter() {
+            pending.push(LogRecord::Prepared {
+                transaction_id: transaction_id.clone(),
+                coordinator: transaction.coordinator.clone(),
+                operations: transaction.operations.clone(),
+            });
+        }
+        for (transaction_id, participants) in committed.iter() {
+            pending.push(LogRecord::Committed {
+                transaction_id: transaction_id.clone(),
+                participants: participants.clone(),
+            });
+        }
+        self.log.rewrite(&pending)
+    }
+
+    /// Commits a transaction, over two-phase commit if keys it writes or
+    /// watches are owned by other nodes. Applied-mode transactions never use
+    /// two-phase commit: their writes were already applied on this node as
+    /// they were made and replicated to the owners like any other write, so
+    /// the commit only checks them here and owners neither validate nor lock
+    /// them.
+    pub async fn commit(&self, transaction_id: &str) -> Result<Vec<Operation>, TransactionError> {
+        let transaction = self.cache.transaction_manager.commit(transaction_id)?;
+        let local = self.replicator.local_node();
+        let groups = self.group(&transaction);
+        if !transaction.before_images.is_empty() || groups.keys().all(|node| node == local) {
+            return self.cache.commit_transaction(transaction);
+        }
+
+        self.preparing
+            .lock()
+            .unwrap()
+            .insert(transaction_id.to_string());
+        let result = self.coordinate(transaction_id, groups).await;
+        self.preparing.lock().unwrap().remove(transaction_id);
+
+        let outcome = if result.is_ok() {
+            "committed"
+        } else {
+            "aborted"
+        };
+        self.cache.transaction_manager.record_outcome(outcome);
+        result.map(|_| transaction.operations)
+    }
+
+    /// Splits the writes and watches of a transaction by the owner of each key.
+    fn group(&self, transaction: &Transaction) -> HashMap<String, PrepareRequest> {
+        let local = self.replicator.local_node();
+        let start_version = if transaction.isolation == IsolationLevel::Snapshot {
+            transaction.start_version
+        } else {
+            0
+        };
+        let request = || PrepareRequest {
+            transaction_id: transaction.id.clone(),
+            coordinator: local.to_string(),
+            start_version,
+            ..Default::default()
+        };
+        let owner = |key: &str| {
+            self.hasher
+                .get_node(key)
+                .unwrap_or_else(|| local.to_string())
+        };
+
+        let mut groups: HashMap<String, PrepareRequest> = HashMap::new();
+        for operation in &transaction.operations {
+            groups
+                .entry(owner(operation.key()))
+                .or_insert_with(request)
+                .operations
+                .push(to_proto(operation));
+        }
+        for (key, version) in &transaction.watched {
+            groups
+                .entry(owner(key))
+                .or_insert_with(request)
+                .watched
+                .insert(key.clone(), *version);
+        }
+        groups
+    }
+
+    async fn coordinate(
+        &self,
+        transaction_id: &str,
+        groups: HashMap<String, PrepareRequest>,
+    ) -> Result<(), TransactionError> {
+        let votes = join_all(groups.into_iter().map(|(node, request)| async move {
+            let vote = self.send_prepare(&node, request).await;
+            (node, vote)
+        }))
+        .await;
+
+        let mut participants = Vec::new();
+        let mut failure = None;
+        for (node, vote) in votes {
+            match vote {
+                Ok(()) => participants.push(node),
+                Err(e) => {
+                    warn!(
+                        "Participant {} refused transaction {}: {}",
+                        node, transaction_id, e
+                    );
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+
+        if failure.is_none() {
+            let mut committed = self.committed.lock().unwrap();
+            let record = LogRecord::Committed {
+                transaction_id: transaction_id.to_string(),
+                participants: participants.clone(),
+            };
+            match self.log.append(&record) {
+                Ok(()) => {
+                    committed.insert(transaction_id.to_string(), participants.clone());
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to log the commit of transaction {}: {}",
+                        transaction_id, e
+                    );
+                    failure = Some(TransactionError::Unavailable(
+                        "the decision log is not writable".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(failure) = failure {
+            // Participants that miss the abort learn it when they ask
+            join_all(
+                participants
+                    .iter()
+                    .map(|node| self.send_decide(node, transaction_id, false)),
+            )
+            .await;
+            return Err(failure);
+        }
+
+        self.deliver(transaction_id).await;
+        Ok(())
+    }
+
+    /// Sends a logged commit to the participants that have not acknowledged
+    /// it yet, and closes it once all have.
+    async fn deliver(&self, transaction_id: &str) {
+        let Some(participants) = self.committed.lock().unwrap().get(transaction_id).cloned() else {
+            return;
+        };
+        let acks = join_all(
+            participants
+                .iter()
+                .map(|node| self.send_decide(node, transaction_id, true)),
+        )
+        .await;
+        let remaining: Vec<String> = participants
+            .into_iter()
+            .zip(acks)
+            .filter(|(_, ack)| !ack)
+            .map(|(node, _)| node)
+            .collect();
+
+        let mut committed = self.committed.lock().unwrap();
+        if !remaining.is_empty() {
+            warn!(
+                "Commit of transaction {} not yet acknowledged by {:?}; retrying",
+                transaction_id, remaining
+            );
+            committed.insert(transaction_id.to_string(), remaining);
+            return;
+        }
+        committed.remove(transaction_id);
+        let record = LogRecord::Done {
+            transaction_id: transaction_id.to_string(),
+        };
+        if let Err(e) = self.log.append(&record) {
+            warn!(
+                "Failed to log the end of transaction {}: {}",
+                transaction_id, e
+            );
+        }
+    }
+
+    async fn send_prepare(
+        &self,
+        node: &str,
+        request: PrepareRequest,
+    ) -> Result<(), TransactionError> {
+        let response = if node == self.replicator.local_node() {
+            self.prepare(request)
+        } else {
+            let Some(mut client) = self.replicator.connect(node).await else {
+                return Err(TransactionError::Unavailable(format!(
+                    "participant {} is unreachable",
+                    node
+                )));
+            };
+            match client.prepare(self.replicator.peer_request(request)).await {
+                Ok(response) => response.into_inner(),
+                Err(e) => {
+                    return Err(TransactionError::Unavailable(format!(
+                        "participant {} failed to prepare: {}",
+                        node,
+                        e.message()
+                    )))
+                }
+            }
+        };
+        match response {
+            PrepareResponse { vote: true, .. } => Ok(()),
+            PrepareResponse { conflict_key, .. } if !conflict_key.is_empty() => {
+                Err(TransactionError::Conflict(conflict_key))
+            }
+            PrepareResponse { reason, .. } => Err(TransactionError::Unavailable(format!(
+                "participant {} failed to prepare: {}",
+                node, reason
+            ))),
+        }
+    }
+
+    async fn send_decide(&self, node: &str, transaction_id: &str, commit: bool) -> bool {
+        if node == self.replicator.local_node() {
+            self.decide(transaction_id, commit);
+            return true;
+        }
+        let Some(mut client) = self.replicator.connect(node).await else {
+            return false;
+        };
+        let request = DecideRequest {
+            transaction_id: transaction_id.to_string(),
+            commit,
+        };
+        match client.decide(self.replicator.peer_request(request)).await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!(
+                    "Failed to send decision on transaction {} to node {}: {}",
+                    transaction_id, node, e
+                );
+                false
+            }
+        }
+    }
+
+    /// Participant side of the first phase: validates the writes against
+    /// the local state, locks their keys and logs them.
+    pub fn prepare(&self, request: PrepareRequest) -> PrepareResponse {
+        let transaction_id = request.transaction_id;
+        let mut prepared = self.prepared.lock().unwrap();
+        if prepared.contains_key(&transaction_id) {
+            return PrepareResponse {
+                vote: true,
+                ..Default::default()
+            };
+        }
+
+        let operations: Vec<Operation> = request.operations.into_iter().map(from_proto).collect();
+        let snapshot_start = (request.start_version > 0).then_some(request.start_version);
+        if let Err(e) = self.cache.prepare(
+            &transaction_id,
+            &operations,
+            &request.watched,
+            snapshot_start,
+        ) {
+            let conflict_key = match &e {
+                TransactionError::Conflict(key) => key.clone(),
+                _ => String::new(),
+            };
+            return PrepareResponse {
+                vote: false,
+                conflict_key,
+                reason: e.to_string(),
+            };
+        }
+
+        let record = LogRecord::Prepared {
+            transaction_id: transaction_id.clone(),
+            coordinator: request.coordinator.clone(),
+            operations: operations.clone(),
+        };
+        if let Err(e) = self.log.append(&record) {
+            error!(
+                "Failed to log the prepare of transaction {}: {}",
+                transaction_id, e
+            );
+            self.cache.unlock_keys(&transaction_id);
+            return PrepareResponse {
+                vote: false,
+                reason: "the decision log is not writable".to_string(),
+                ..Default::default()
+            };
+        }
+        prepared.insert(
+            transaction_id,
+            Prepared {
+                coordinator: request.coordinator,
+                operations,
+                since: Instant::now(),
+            },
+        );
+        PrepareResponse {
+            vote: true,
+            ..Default::default()
+        }
+    }
+
+    /// Participant side of the second phase: applies or drops the prepared
+    /// writes and releases their keys. Decisions on transactions that are not
+    /// prepared here, such as repeated ones, are ignored.
+    pub fn decide(&self, transaction_id: &str, commit: bool) {
+        let mut transactions = self.prepared.lock().unwrap();
+        let Some(prepared) = transactions.remove(transaction_id) else {
+            return;
+        };
+        if commit {
+            self.cache
+                .commit_prepared(transaction_id, &prepared.operations);
+        } else {
+            self.cache.unlock_keys(transaction_id);
+        }
+        let record = LogRecord::Resolved {
+            transaction_id: transaction_id.to_string(),
+        };
+        if let Err(e) = self.log.append(&record) {
+            warn!(
+                "Failed to log the resolution of transaction {}: {}",
+                transaction_id, e
+            );
+        }
+        drop(transactions);
+        info!(
+            "{} prepared transaction {} from coordinator {}",
+            if commit { "Committed" } else { "Aborted" },
+            transaction_id,
+            prepared.coordinator
+        );
+    }
+
+    /// Coordinator side of recovery: what became of a transaction.
+    pub fn decision(&self, transaction_id: &str) -> DecisionResponse {
+        if self.committed.lock().unwrap().contains_key(transaction_id) {
+            DecisionResponse {
+                known: true,
+                commit: true,
+            }
+        } else if self.preparing.lock().unwrap().contains(transaction_id) {
+            DecisionResponse {
+                known: false,
+                commit: false,
+            }
+        } else {
+            DecisionResponse {
+                known: true,
+                commit: false,
+            }
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = interval(self.interval);
+        loop {
+            ticker.tick().await;
+            self.recover().await;
+        }
+    }
+
+    /// Re-sends unacknowledged commits, resolves transactions that stayed
+    /// prepared for a full interval by asking their coordinator, and compacts
+    /// the log once it has grown. A transaction whose coordinator has left
+    /// the cluster is presumed aborted after the in-doubt timeout, even
+    /// though the coordinator may have committed it on other participants.
+    async fn recover(&self) {
+        let committed: Vec<String> = self.committed.lock().unwrap().keys().cloned().collect();
+        for transaction_id in committed {
+            self.deliver(&transaction_id).await;
+        }
+
+        let in_doubt: Vec<(String, String, Duration)> = self
+            .prepared
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, prepared)| prepared.since.elapsed() >= self.interval)
+            .map(|(id, prepared)| {
+                let waited = prepared.since.elapsed();
+                (id.clone(), prepared.coordinator.clone(), waited)
+            })
+            .collect();
+        for (transaction_id, coordinator, waited) in in_doubt {
+            match self.query(&coordinator, &transaction_id).await {
+                Some(DecisionResponse {
+                    known: true,
+                    commit,
+                }) => self.decide(&transaction_id, commit),
+                _ if !self.in_doubt_timeout.is_zero()
+                    && waited >= self.in_doubt_timeout
+                    && self.hasher.weight_of(&coordinator).is_none() =>
+                {
+                    warn!(
+                        "Coordinator {} of in-doubt transaction {} has left; presuming it aborted",
+                        coordinator, transaction_id
+                    );
+                    self.decide(&transaction_id, false);
+                }
+                _ => warn!(
+                    "Transaction {} is in doubt; coordinator {} has not decided",
+                    transaction_id, coordinator
+                ),
+            }
+        }
+
+        if self.log.appended.load(Ordering::Relaxed) >= COMPACT_AFTER {
+            if let Err(e) = self.compact() {
+                warn!("Failed to compact the transaction log: {}", e);
+            }
+        }
+    }
+
+    async fn query(&self, coordinator: &str, transaction_id: &str) -> Option<DecisionResponse> {
+        if coordinator == self.replicator.local_node() {
+            return Some(self.decision(transaction_id));
+        }
+        let mut client = self.replicator.connect(coordinator).await?;
+        let request = DecisionQuery {
+            transaction_id: transaction_id.to_string(),
+        };
+        match client
+            .get_decision(self.replicator.peer_request(request))
+            .await
+        {
+            Ok(response) => Some(response.into_inner()),
+            Err(e) => {
+                warn!(
+                    "Failed to ask coordinator {} about transaction {}: {}",
+                    coordinator, transaction_id, e
+                );
+                None
+            }
+        }
+    }
+}
+
+fn to_proto(operation: &Operation) -> TransactionOperation {
+    match operation {
+        Operation::Put { key, value, ttl } => TransactionOperation {
+            key: key.clone(),
+            value: value.to_vec(),
+            ttl: ttl.map_or(0, |ttl| ttl.as_secs() as i64),
+            evict: false,
+        },
+        Operation::Evict { key } => TransactionOperation {
+            key: key.clone(),
+            evict: true,
+            ..Default::default()
+        },
+    }
+}
+
+fn from_proto(operation: TransactionOperation) -> Operation {
+    if operation.evict {
+        Operation::Evict { key: operation.key }
+    } else {
+        Operation::Put {
+            key: operation.key,
+            value: Bytes::from(operation.value),
+            ttl: (operation.ttl > 0).then(|| Duration::from_secs(operation.ttl as u64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::counter::PnCounter;
+    use crate::hashing::HashAlgorithm;
+    use crate::monitoring::Monitoring;
+    use crate::placement::RingPlacement;
+    use crate::replication::Mutation;
+
+    const NODE: &str = "http://127.0.0.1:50051";
+
+    fn log_path() -> String {
+        let path = std::env::temp_dir().join(format!("2pc-{}.log", uuid::Uuid::new_v4()));
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Starts this node on `log` with an empty cache, as after a restart.
+    fn start(log: &str, configure: impl FnOnce(&mut Config)) -> TwoPhaseCommit {
+        let mut config = Config::load();
+        config.transaction_log_path = log.to_string();
+        configure(&mut config);
+        let monitoring = Arc::new(Monitoring::new());
+        let (events, _) = crossbeam::channel::unbounded();
+        let (replication, _) = tokio::sync::mpsc::unbounded_channel();
+        let cache = Arc::new(Cache::new(
+            config.clone(),
+            events,
+            replication,
+            monitoring.clone(),
+            NODE.to_string(),
+        ));
+        let hasher = Arc::new(ConsistentHashing::new(
+            100,
+            HashAlgorithm::XxHash64,
+            Box::new(RingPlacement),
+            0,
+        ));
+        hasher.set_weight(NODE.to_string(), 1.0);
+        let replicator = Arc::new(Replicator::new(
+            hasher.clone(),
+            1,
+            NODE.to_string(),
+            monitoring,
+            None,
+            None,
+        ));
+        TwoPhaseCommit::new(cache, hasher, replicator, &config).unwrap()
+    }
+
+    fn prepare(two_phase: &TwoPhaseCommit, transaction_id: &str, coordinator: &str) {
+        let operation = Operation::Put {
+            key: "key".to_string(),
+            value: Bytes::from("value"),
+            ttl: None,
+        };
+        let response = two_phase.prepare(PrepareRequest {
+            transaction_id: transaction_id.to_string(),
+            coordinator: coordinator.to_string(),
+            operations: vec![to_proto(&operation)],
+            ..Default::default()
+        });
+        assert!(response.vote, "{}", response.reason);
+    }
+
+    /// Makes a prepared transaction look in doubt for `age`.
+    fn age(two_phase: &TwoPhaseCommit, transaction_id: &str, age: Duration) {
+        let mut prepared = two_phase.prepared.lock().unwrap();
+        prepared.get_mut(transaction_id).unwrap().since = Instant::now() - age;
+    }
+
+    fn log_lines(log: &str) -> usize {
+        std::fs::read_to_string(log).unwrap().lines().count()
+    }
+
+    #[test]
+    fn participant_keeps_prepared_writes_across_a_crash() {
+        let log = log_path();
+        let two_phase = start(&log, |_| {});
+        prepare(&two_phase, "t1", NODE);
+        drop(two_phase);
+
+        let two_phase = start(&log, |_| {});
+        assert!(matches!(
+            two_phase
+                .cache
+                .put("key".to_string(), Bytes::from("other"), None),
+            Err(TransactionError::Locked(_))
+        ));
+        two_phase.decide("t1", true);
+        assert_eq!(two_phase.cache.get("key"), Some(Bytes::from("value")));
+        drop(two_phase);
+
+        let two_phase = start(&log, |_| {});
+        assert!(two_phase.prepared.lock().unwrap().is_empty());
+        assert_eq!(log_lines(&log), 0);
+    }
+
+    #[tokio::test]
+    async fn coordinator_crash_before_commit_aborts() {
+        let log = log_path();
+        let two_phase = start(&log, |_| {});
+        prepare(&two_phase, "t1", NODE);
+        drop(two_phase);
+
+        // Nothing was logged as committed: the coordinator presumes abort
+        let two_phase = start(&log, |_| {});
+        let decision = two_phase.decision("t1");
+        assert!(decision.known && !decision.commit);
+        age(&two_phase, "t1", Duration::from_secs(60));
+        two_phase.recover().await;
+        assert!(two_phase.prepared.lock().unwrap().is_empty());
+        assert_eq!(two_phase.cache.get("key"), None);
+        assert!(two_phase
+            .cache
+            .put("key".to_string(), Bytes::from("other"), None)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn coordinator_crash_after_commit_redelivers_it() {
+        let log = log_path();
+        let two_phase = start(&log, |_| {});
+        prepare(&two_phase, "t1", NODE);
+        let record = LogRecord::Committed {
+            transaction_id: "t1".to_string(),
+            participants: vec![NODE.to_string()],
+        };
+        two_phase.log.append(&record).unwrap();
+        drop(two_phase);
+
+        let two_phase = start(&log, |_| {});
+        let decision = two_phase.decision("t1");
+        assert!(decision.known && decision.commit);
+        two_phase.recover().await;
+        assert_eq!(two_phase.cache.get("key"), Some(Bytes::from("value")));
+        assert!(two_phase.committed.lock().unwrap().is_empty());
+        drop(two_phase);
+
+        let two_phase = start(&log, |_| {});
+        assert!(two_phase.prepared.lock().unwrap().is_empty());
+        assert!(two_phase.committed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeated_decisions_are_ignored() {
+        let log = log_path();
+        let two_phase = start(&log, |_| {});
+        prepare(&two_phase, "t1", NODE);
+        two_phase.decide("t1", true);
+        let (_, version) = two_phase.cache.get_versioned("key").unwrap();
+
+        two_phase.decide("t1", true);
+        two_phase.decide("t1", false);
+        assert_eq!(
+            two_phase.cache.get_versioned("key"),
+            Some((Bytes::from("value"), version))
+        );
+    }
+
+    #[tokio::test]
+    async fn departed_coordinator_is_presumed_to_abort() {
+        let log = log_path();
+        let two_phase = start(&log, |config| config.in_doubt_timeout = 30);
+        // Refuses connections and is not a member
+        let coordinator = "http://127.0.0.1:1";
+        prepare(&two_phase, "t1", coordinator);
+
+        age(&two_phase, "t1", Duration::from_secs(10));
+        two_phase.recover().await;
+        assert!(two_phase.prepared.lock().unwrap().contains_key("t1"));
+
+        age(&two_phase, "t1", Duration::from_secs(60));
+        two_phase.recover().await;
+        assert!(two_phase.prepared.lock().unwrap().is_empty());
+        assert_eq!(two_phase.cache.get("key"), None);
+    }
+
+    #[test]
+    fn writes_replicated_during_an_abort_keep_their_versions() {
+        let two_phase = start(&log_path(), |_| {});
+        two_phase.cache.increment("counter", 2, 0, None).unwrap();
+        let lock = |transaction_id: &str| {
+            let operations = ["key", "counter"].map(|key| Operation::Evict {
+                key: key.to_string(),
+            });
+            two_phase.cache.lock_keys(transaction_id, &operations);
+        };
+        lock("t1");
+
+        let put = Mutation::put("key".to_string(), b"remote", None, 5);
+        let mut counter = PnCounter::new(0, 0);
+        counter.add("http://127.0.0.1:50052", 3).unwrap();
+        let counter = Mutation::Counter {
+            key: "counter".to_string(),
+            counter,
+            ttl: None,
+            version: 6,
+        };
+        assert!(two_phase.cache.apply_remote(put));
+        assert!(two_phase.cache.apply_remote(counter));
+        assert_eq!(two_phase.cache.get("key"), None);
+
+        two_phase.cache.unlock_keys("t1");
+        assert_eq!(
+            two_phase.cache.get_versioned("key"),
+            Some((Bytes::from("remote"), 5))
+        );
+        // Merged with the local increment rather than replacing it
+        assert_eq!(two_phase.cache.get("counter"), Some(Bytes::from("5")));
+    }
+
+    #[test]
+    fn writes_replicated_during_a_commit_are_ordered_after_it() {
+        let two_phase = start(&log_path(), |_| {});
+        prepare(&two_phase, "t1", NODE);
+        let put = Mutation::put("key".to_string(), b"remote", None, 5);
+        assert!(two_phase.cache.apply_remote(put));
+
+        two_phase.decide("t1", true);
+        let (value, version) = two_phase.cache.get_versioned("key").unwrap();
+        assert_eq!(value, Bytes::from("remote"));
+        assert!(version > 5);
+    }
+
+    #[test]
+    fn compaction_drops_resolved_transactions() {
+        let log = log_path();
+        let two_phase = start(&log, |_| {});
+        prepare(&two_phase, "t1", NODE);
+        two_phase.decide("t1", true);
+        prepare(&two_phase, "t2", NODE);
+        assert_eq!(log_lines(&log), 3);
+
+        two_phase.compact().unwrap();
+        assert_eq!(log_lines(&log), 1);
+    }
+}